@@ -1,13 +1,29 @@
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledEngine};
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkGroup, Criterion};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsRequest, KvsResponse, KvsServer, SledEngine, SyncPolicy,
+};
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use rand::rngs::StdRng;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 const READ_NUM: u32 = 1000;
 const SET_NUM: u32 = 100;
 const LEN: u32 = 100000;
+const WRITER_NUM: usize = 8;
+const JOB_NUM: u64 = 1000;
+const POOL_THREADS: u32 = 4;
+const NETWORK_ADDR: &str = "127.0.0.1:4100";
+#[cfg(feature = "async")]
+const ASYNC_NETWORK_ADDR: &str = "127.0.0.1:4101";
 
 pub fn set_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_benchmark");
@@ -43,9 +59,60 @@ pub fn set_benchmark(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    // With fsync on every write, concurrent writers share fsyncs through group commit.
+    group.bench_function("kvs_sync_always", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let kvs = generate_random_key_values();
+                (open_sync_always(&temp_dir), kvs)
+            },
+            |(store, kvs)| {
+                for (k, v) in kvs {
+                    store.set(k, v).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("kvs_sync_always_concurrent", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let kvs = generate_random_key_values();
+                (open_sync_always(&temp_dir), kvs)
+            },
+            |(store, kvs)| {
+                let chunk_size = (kvs.len() + WRITER_NUM - 1) / WRITER_NUM;
+                let handles: Vec<_> = kvs
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        let store = store.clone();
+                        let chunk = chunk.to_vec();
+                        thread::spawn(move || {
+                            for (k, v) in chunk {
+                                store.set(k, v).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
+fn open_sync_always(temp_dir: &TempDir) -> KvStore {
+    KvStoreOptions::new()
+        .sync_policy(SyncPolicy::Always)
+        .open(temp_dir.path())
+        .unwrap()
+}
+
 pub fn get_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_benchmark");
 
@@ -87,6 +154,123 @@ pub fn get_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
+/// Gets over one persistent connection, waiting for each response or pipelined.
+pub fn network_benchmark(c: &mut Criterion) {
+    let addr: SocketAddr = NETWORK_ADDR.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..SET_NUM {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    thread::spawn(move || {
+        let server =
+            KvsServer::new(store, NaiveThreadPool::new(1).unwrap()).max_requests(u64::max_value());
+        server.run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = BufWriter::new(stream);
+    let requests: Vec<KvsRequest> = (0..READ_NUM)
+        .map(|i| KvsRequest::Get {
+            key: format!("key{}", i % SET_NUM).into_bytes(),
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("network_benchmark");
+    group.sample_size(10);
+    group.bench_function("get_round_trip", |b| {
+        b.iter(|| {
+            for request in &requests {
+                request.write_to(&mut writer).unwrap();
+                writer.flush().unwrap();
+                KvsResponse::read_from(&mut reader).unwrap();
+            }
+        })
+    });
+    group.bench_function("get_pipelined", |b| {
+        b.iter(|| {
+            for request in &requests {
+                request.write_to(&mut writer).unwrap();
+            }
+            writer.flush().unwrap();
+            for _ in &requests {
+                KvsResponse::read_from(&mut reader).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+/// `network_benchmark` against `AsyncKvsServer` with `AsyncKvsClient`.
+#[cfg(feature = "async")]
+pub fn async_network_benchmark(c: &mut Criterion) {
+    use kvs::{AsyncKvsClient, AsyncKvsServer};
+    use tokio::runtime::Runtime;
+
+    let addr: SocketAddr = ASYNC_NETWORK_ADDR.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..SET_NUM {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    thread::spawn(move || {
+        let server = AsyncKvsServer::new(store).max_requests(u64::max_value());
+        Runtime::new().unwrap().block_on(server.run(addr)).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut runtime = Runtime::new().unwrap();
+    let mut client = runtime.block_on(AsyncKvsClient::connect(addr)).unwrap();
+    let requests: Vec<KvsRequest> = (0..READ_NUM)
+        .map(|i| KvsRequest::Get {
+            key: format!("key{}", i % SET_NUM).into_bytes(),
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("async_network_benchmark");
+    group.sample_size(10);
+    group.bench_function("get_round_trip", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for i in 0..READ_NUM {
+                    let key = format!("key{}", i % SET_NUM);
+                    client.get(key.as_bytes()).await.unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("get_pipelined", |b| {
+        b.iter(|| runtime.block_on(client.pipeline(&requests)).unwrap())
+    });
+    group.finish();
+}
+
+/// Spawns many small jobs into each thread pool and waits for their results.
+pub fn thread_pool_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread_pool_benchmark");
+    bench_pool::<NaiveThreadPool>(&mut group, "naive");
+    bench_pool::<SharedQueueThreadPool>(&mut group, "shared_queue");
+    bench_pool::<RayonThreadPool>(&mut group, "rayon");
+    bench_pool::<WorkStealingThreadPool>(&mut group, "work_stealing");
+    group.finish();
+}
+
+fn bench_pool<P: ThreadPool>(group: &mut BenchmarkGroup<WallTime>, name: &str) {
+    let pool = P::new(POOL_THREADS).unwrap();
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let handles: Vec<_> = (0..JOB_NUM)
+                .map(|i| pool.spawn_with_handle(move || (0..i).sum::<u64>()))
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        })
+    });
+}
+
 fn generate_random_key_values() -> Vec<(String, String)> {
     let mut result = vec![];
     let mut rand_len = StdRng::seed_from_u64(1);
@@ -108,5 +292,21 @@ fn generate_random_key_values() -> Vec<(String, String)> {
     result
 }
 
-criterion_group!(benches, set_benchmark, get_benchmark);
+#[cfg(not(feature = "async"))]
+criterion_group!(
+    benches,
+    set_benchmark,
+    get_benchmark,
+    network_benchmark,
+    thread_pool_benchmark
+);
+#[cfg(feature = "async")]
+criterion_group!(
+    benches,
+    set_benchmark,
+    get_benchmark,
+    network_benchmark,
+    async_network_benchmark,
+    thread_pool_benchmark
+);
 criterion_main!(benches);
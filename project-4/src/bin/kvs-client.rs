@@ -1,18 +1,38 @@
-use clap::{App, Arg, SubCommand};
+use clap::{value_t, App, Arg, ArgMatches, SubCommand};
 use kvs::*;
-use serde::Deserialize;
-use serde_json;
 use std::env;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpStream};
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
 use std::process::exit;
-
-#[macro_use]
-extern crate failure;
+use std::str::FromStr;
+use std::time::Duration;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+/// Number of requests `exec` sends before reading their responses.
+const PIPELINE_DEPTH: usize = 128;
+
+/// Exit code when the key of `rm` does not exist.
+const EXIT_KEY_NOT_FOUND: i32 = 2;
+/// Exit code when the server failed to handle the request.
+const EXIT_SERVER_ERROR: i32 = 3;
+/// Exit code when the server cannot be reached or answers nonsense.
+const EXIT_CONNECTION_ERROR: i32 = 4;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        exit(match e {
+            KvsError::KeyNotFound => EXIT_KEY_NOT_FOUND,
+            KvsError::ServerError { .. } => EXIT_SERVER_ERROR,
+            KvsError::Io(_) | KvsError::Serialization(_) | KvsError::Protocol(_) => {
+                EXIT_CONNECTION_ERROR
+            }
+            _ => 1,
+        });
+    }
+}
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let matches = App::new("kvs-client")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -22,6 +42,11 @@ fn main() -> Result<()> {
                 .args(&[
                     Arg::with_name("key").help("A string key").required(true),
                     Arg::with_name("value").help("A string key").required(true),
+                    Arg::with_name("ttl")
+                        .help("Seconds after which the key expires")
+                        .long("ttl")
+                        .takes_value(true)
+                        .value_name("SECONDS"),
                     Arg::with_name("addr")
                         .help("Server address")
                         .long("addr")
@@ -55,64 +80,94 @@ fn main() -> Result<()> {
                 ])
                 .about("Remove a given key"),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .args(&[
+                    Arg::with_name("start")
+                        .help("First key of the range")
+                        .long("start")
+                        .takes_value(true)
+                        .value_name("KEY"),
+                    Arg::with_name("end")
+                        .help("Key after the last one of the range")
+                        .long("end")
+                        .takes_value(true)
+                        .value_name("KEY"),
+                    Arg::with_name("prefix")
+                        .help("Prefix of the keys")
+                        .long("prefix")
+                        .takes_value(true)
+                        .value_name("PREFIX")
+                        .conflicts_with_all(&["start", "end"]),
+                    Arg::with_name("limit")
+                        .help("Maximum number of keys")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N"),
+                    Arg::with_name("addr")
+                        .help("Server address")
+                        .long("addr")
+                        .value_name("IP-PORT")
+                        .default_value(DEFAULT_ADDRESS),
+                ])
+                .about("List the key-value pairs of a key range or prefix in key order"),
+        )
+        .subcommand(
+            SubCommand::with_name("exec")
+                .args(&[Arg::with_name("addr")
+                    .help("Server address")
+                    .long("addr")
+                    .value_name("IP-PORT")
+                    .default_value(DEFAULT_ADDRESS)])
+                .about("Run `set KEY VALUE`, `get KEY` and `rm KEY` lines from stdin over one connection"),
+        )
         .get_matches();
 
     if let (cmd, Some(_matches)) = matches.subcommand() {
         let addr = _matches.value_of("addr").expect("Addr is missing");
         let addr: SocketAddr = addr.parse().expect("Addr format is wrong");
-        let mut stream = TcpStream::connect(addr)?;
+        let mut client = KvsClient::connect(addr)?;
 
         match cmd {
             "set" => {
                 let key = _matches.value_of("key").expect("Key is missing");
                 let value = _matches.value_of("value").expect("Value is missing");
-                let request = KvsRequest::Set {
-                    key: key.to_owned(),
-                    value: value.to_owned(),
-                };
-                serde_json::to_writer(&mut stream, &request)?;
-                // let mut store = KvStore::open(env::current_dir()?)?;
-                // store.set(key.to_owned(), value.to_owned())?;
-                parse_response(&mut stream)?;
+                match parse_arg(_matches, "ttl") {
+                    Some(ttl) => client.set_with_ttl(
+                        key.as_bytes(),
+                        value.as_bytes(),
+                        Duration::from_secs(ttl),
+                    )?,
+                    None => client.set(key.as_bytes(), value.as_bytes())?,
+                }
             }
             "get" => {
                 let key = _matches.value_of("key").expect("Key is missing");
-                // let mut store = KvStore::open(env::current_dir()?)?;
-                // match store.get(key.to_owned())? {
-                //     Some(value) => {
-                //         println!("{}", value);
-                //     }
-                //     None => {
-                //         println!("Key not found");
-                //     }
-                // }
-                let request = KvsRequest::Get {
-                    key: key.to_owned(),
-                };
-                serde_json::to_writer(&mut stream, &request)?;
-                stream.flush()?;
-                match parse_response(&mut stream)? {
-                    Some(value) => {
-                        println!("{}", value);
-                    }
-                    None => {
-                        println!("Key not found");
-                    }
-                }
+                print_value(client.get(key.as_bytes())?)?;
             }
             "rm" => {
                 let key = _matches.value_of("key").expect("Key is missing");
-                // let mut store = KvStore::open(env::current_dir()?)?;
-                // if let Err(_) = store.remove(key.to_owned()) {
-                //     println!("Key not found");
-                //     exit(1);
-                // }
-                let request = KvsRequest::Remove {
-                    key: key.to_owned(),
+                client.remove(key.as_bytes())?;
+            }
+            "scan" => {
+                let limit = parse_arg(_matches, "limit");
+                let entries = match _matches.value_of("prefix") {
+                    Some(prefix) => client.scan_prefix(prefix.as_bytes(), limit)?,
+                    None => client.scan(
+                        _matches.value_of("start").map(str::as_bytes),
+                        _matches.value_of("end").map(str::as_bytes),
+                        limit,
+                    )?,
                 };
-                serde_json::to_writer(&mut stream, &request)?;
-                parse_response(&mut stream)?;
+                let mut stdout = io::stdout();
+                for (key, value) in entries {
+                    stdout.write_all(&key)?;
+                    stdout.write_all(b"\t")?;
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
             }
+            "exec" => exec(&mut client)?,
             _ => unreachable!(),
         }
     } else {
@@ -121,11 +176,81 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn parse_response(stream: &mut TcpStream) -> Result<Option<String>> {
-    let mut de = serde_json::Deserializer::from_reader(stream);
-    let response = KvsResponse::deserialize(&mut de)?;
-    match response {
-        KvsResponse::Ok(value) => Ok(value),
-        KvsResponse::Err(e) => Err(format_err!("{}", e)),
+/// Runs the `set`, `get` and `rm` commands read from stdin, one per line.
+///
+/// Up to `PIPELINE_DEPTH` requests are sent before waiting for their responses, so
+/// a long script costs few round trips.
+fn exec(client: &mut KvsClient) -> Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        let mut requests = Vec::with_capacity(PIPELINE_DEPTH);
+        for line in lines.by_ref().take(PIPELINE_DEPTH) {
+            let line = line?;
+            let mut args = line.splitn(3, ' ');
+            let request = match (args.next(), args.next(), args.next()) {
+                (Some("set"), Some(key), Some(value)) => KvsRequest::Set {
+                    key: key.as_bytes().to_vec(),
+                    value: value.as_bytes().to_vec(),
+                    ttl: None,
+                },
+                (Some("get"), Some(key), None) => KvsRequest::Get {
+                    key: key.as_bytes().to_vec(),
+                },
+                (Some("rm"), Some(key), None) => KvsRequest::Remove {
+                    key: key.as_bytes().to_vec(),
+                },
+                (None, ..) | (Some(""), ..) => continue,
+                _ => {
+                    eprintln!("Invalid command: {}", line);
+                    exit(1);
+                }
+            };
+            requests.push(request);
+        }
+        if requests.is_empty() {
+            return Ok(());
+        }
+        let responses = client.pipeline(&requests)?;
+        for (request, response) in requests.iter().zip(responses) {
+            match (request, response) {
+                (KvsRequest::Get { .. }, KvsResponse::Value(value)) => print_value(Some(value))?,
+                (KvsRequest::Get { .. }, KvsResponse::NotFound) => print_value(None)?,
+                (KvsRequest::Set { .. }, KvsResponse::Set)
+                | (KvsRequest::Remove { .. }, KvsResponse::Removed) => {}
+                (KvsRequest::Remove { .. }, KvsResponse::NotFound) => {
+                    return Err(KvsError::KeyNotFound)
+                }
+                (_, KvsResponse::Err { code, message }) => {
+                    return Err(KvsError::ServerError { code, message })
+                }
+                (_, response) => {
+                    return Err(KvsError::Protocol(format!(
+                        "Unexpected response {:?}",
+                        response
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Prints the value of a `get`, or "Key not found".
+fn print_value(value: Option<Vec<u8>>) -> Result<()> {
+    match value {
+        Some(value) => {
+            let mut stdout = io::stdout();
+            stdout.write_all(&value)?;
+            stdout.write_all(b"\n")?;
+        }
+        None => println!("Key not found"),
     }
+    Ok(())
+}
+
+/// Parses an optional argument, exiting with a usage error if it is malformed.
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches
+        .value_of(name)
+        .map(|_| value_t!(matches, name, T).unwrap_or_else(|e| e.exit()))
 }
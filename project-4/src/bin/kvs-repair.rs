@@ -0,0 +1,20 @@
+use kvs::{KvStore, Result};
+use log::info;
+use simplelog::{Config, LevelFilter, TerminalMode};
+use std::env;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-repair", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "Truncates corrupt records out of a kvs data directory")]
+struct Opt {}
+
+fn main() -> Result<()> {
+    simplelog::TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Stderr)
+        .unwrap();
+    Opt::from_args();
+
+    let curr_dir = env::current_dir()?;
+    let dropped = KvStore::repair(&curr_dir)?;
+    info!("Dropped {} bytes from {}", dropped, curr_dir.display());
+    Ok(())
+}
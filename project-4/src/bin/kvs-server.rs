@@ -1,9 +1,11 @@
-use failure::format_err;
-use kvs::thread_pool::ThreadPool;
+use kvs::thread_pool::{
+    NaiveThreadPool, OverflowPolicy, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+};
 use kvs::*;
 use log::info;
 use simplelog::{Config, LevelFilter, TerminalMode};
 use std::net::SocketAddr;
+use std::time::Duration;
 use std::{
     env, fs,
     io::{Read, Write},
@@ -13,6 +15,10 @@ use structopt::StructOpt;
 
 const DEFAULT_ENGINE: &str = "kvs";
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+const DEFAULT_SYNC_POLICY: &str = "never";
+const DEFAULT_RUNTIME: &str = "sync";
+const DEFAULT_POOL: &str = "naive";
+const DEFAULT_OVERFLOW_POLICY: &str = "block";
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -27,6 +33,59 @@ struct Opt {
     addr: SocketAddr,
     #[structopt(long, help = "Sets the storage engine", value_name = "ENGINE-NAME", default_value = DEFAULT_ENGINE, possible_values = &["kvs","sled"])]
     engine: String,
+    #[structopt(
+        long,
+        help = "Sets when the kvs engine fsyncs writes: always, never, every:N or interval:MS",
+        value_name = "POLICY",
+        default_value = DEFAULT_SYNC_POLICY,
+        parse(try_from_str)
+    )]
+    sync: SyncPolicy,
+    #[structopt(
+        long,
+        help = "Sets how long an idle connection is kept open",
+        value_name = "SECONDS",
+        default_value = "60"
+    )]
+    idle_timeout: u64,
+    #[structopt(
+        long,
+        help = "Sets how many requests are answered on a connection before it is closed",
+        value_name = "N",
+        default_value = "100000"
+    )]
+    max_requests: u64,
+    #[structopt(
+        long,
+        help = "Sets how long shutting down waits for requests in progress",
+        value_name = "SECONDS",
+        default_value = "10"
+    )]
+    shutdown_timeout: u64,
+    #[structopt(long, help = "Sets how connections are served: threads of a pool (sync) or tokio tasks (async)", value_name = "RUNTIME", default_value = DEFAULT_RUNTIME, possible_values = &["sync", "async"])]
+    runtime: String,
+    #[structopt(long, help = "Sets the thread pool of the sync runtime; naive starts a thread per connection", value_name = "POOL-NAME", default_value = DEFAULT_POOL, possible_values = &["naive", "shared", "rayon"])]
+    pool: String,
+    #[structopt(
+        long,
+        help = "Sets the number of threads of the pool [default: number of CPUs]",
+        value_name = "N"
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long,
+        help = "Sets how many connections may wait for a thread of the shared pool [default: no limit]",
+        value_name = "N"
+    )]
+    queue_capacity: Option<usize>,
+    #[structopt(long, help = "Sets what happens to a new connection when the queue is full", value_name = "POLICY", default_value = DEFAULT_OVERFLOW_POLICY, possible_values = &["block", "reject", "drop-oldest"])]
+    overflow_policy: String,
+}
+
+impl Opt {
+    fn threads(&self) -> u32 {
+        self.threads.unwrap_or_else(|| num_cpus::get() as u32)
+    }
 }
 
 fn main() -> Result<()> {
@@ -34,9 +93,22 @@ fn main() -> Result<()> {
         .unwrap();
     let opt = Opt::from_args();
 
-    let engine = opt.engine;
+    let engine = &opt.engine;
     info!("kvs-server version: {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
+    info!("Runtime: {}", opt.runtime);
+    if opt.runtime == "sync" {
+        match opt.pool.as_str() {
+            "naive" => info!("Thread pool: naive"),
+            pool => info!("Thread pool: {} with {} threads", pool, opt.threads()),
+        }
+        if let ("shared", Some(capacity)) = (opt.pool.as_str(), opt.queue_capacity) {
+            info!(
+                "Queue capacity: {}, overflow policy: {}",
+                capacity, opt.overflow_policy
+            );
+        }
+    }
     info!("Listening on {}", opt.addr);
 
     let curr_dir = env::current_dir()?;
@@ -44,23 +116,97 @@ fn main() -> Result<()> {
         "sled" => {
             current_engine_or(&curr_dir, "sled")?;
             let engine = SledEngine::open(&curr_dir)?;
-            run_with_engine(engine, opt.addr)?;
+            run_with_engine(engine, &opt)?;
         }
         "kvs" => {
             current_engine_or(&curr_dir, "kvs")?;
-            let engine = KvStore::open(&curr_dir)?;
-            run_with_engine(engine, opt.addr)?;
+            info!("Sync policy: {}", opt.sync);
+            let engine = KvStoreOptions::new()
+                .sync_policy(opt.sync)
+                .open(&curr_dir)?;
+            run_with_engine(engine, &opt)?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn run_with_engine<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    match opt.runtime.as_str() {
+        "sync" => run_sync(engine, opt),
+        "async" => run_async(engine, opt),
+        _ => unreachable!(),
+    }
+}
+
+fn run_sync<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    let threads = opt.threads();
+    if threads == 0 {
+        return Err(KvsError::Other(
+            "The thread pool needs at least one thread".to_owned(),
+        ));
+    }
+    match opt.pool.as_str() {
+        "naive" => run_with_pool(engine, NaiveThreadPool::new(threads)?, opt),
+        "shared" => {
+            let pool = match opt.queue_capacity {
+                Some(capacity) => {
+                    let overflow = match opt.overflow_policy.as_str() {
+                        "block" => OverflowPolicy::Block,
+                        "reject" => OverflowPolicy::Reject,
+                        "drop-oldest" => OverflowPolicy::DropOldest,
+                        _ => unreachable!(),
+                    };
+                    SharedQueueThreadPool::bounded(threads, capacity, overflow)?
+                }
+                None => SharedQueueThreadPool::new(threads)?,
+            };
+            run_with_pool(engine, pool, opt)
         }
+        "rayon" => run_with_pool(engine, RayonThreadPool::new(threads)?, opt),
         _ => unreachable!(),
     }
+}
+
+fn run_with_pool<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, opt: &Opt) -> Result<()> {
+    let server = KvsServer::new(engine, pool)
+        .idle_timeout(Duration::from_secs(opt.idle_timeout))
+        .max_requests(opt.max_requests)
+        .shutdown_timeout(Duration::from_secs(opt.shutdown_timeout));
+    stop_on_signal(server.shutdown_handle())?;
+    server.run(opt.addr)?;
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Shuts the server down on SIGINT or SIGTERM.
+fn stop_on_signal(handle: ShutdownHandle) -> Result<()> {
+    ctrlc::set_handler(move || {
+        info!("Received termination signal");
+        handle.shutdown();
+    })
+    .map_err(|e| KvsError::Other(format!("Error on setting signal handler: {}", e)))
+}
 
+#[cfg(feature = "async")]
+fn run_async<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    let server = AsyncKvsServer::new(engine)
+        .idle_timeout(Duration::from_secs(opt.idle_timeout))
+        .max_requests(opt.max_requests)
+        .shutdown_timeout(Duration::from_secs(opt.shutdown_timeout));
+    stop_on_signal(server.shutdown_handle())?;
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(server.run(opt.addr))?;
+    info!("Server stopped");
     Ok(())
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let pool = thread_pool::NaiveThreadPool::new(1)?;
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+#[cfg(not(feature = "async"))]
+fn run_async<E: KvsEngine>(_engine: E, _opt: &Opt) -> Result<()> {
+    Err(KvsError::Other(
+        "kvs-server was built without the `async` feature".to_owned(),
+    ))
 }
 
 fn current_engine_or<'a>(path: &Path, engine: &'a str) -> Result<&'a str> {
@@ -76,7 +222,7 @@ fn current_engine_or<'a>(path: &Path, engine: &'a str) -> Result<&'a str> {
         engine_type_file.write(engine.as_bytes())?;
         engine_type_file.flush()?;
     } else if engine_type != String::from(engine) {
-        return Err(format_err!("Wrong engine"));
+        return Err(KvsError::WrongEngine);
     }
     Ok(engine)
 }
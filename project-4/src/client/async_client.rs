@@ -0,0 +1,253 @@
+use super::{
+    can_resend, into_batch_applied, into_cas, into_entries, into_removed, into_result, into_set,
+    into_value, KvsClientOptions,
+};
+use crate::frame::{read_next_frame_async, write_frame};
+use crate::{CompareAndSwapError, KvsRequest, KvsResponse, Result, WriteBatch};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// A `KvsClient` running on the tokio runtime.
+///
+/// It talks to `KvsServer` and `AsyncKvsServer` alike, and reconnects the same way
+/// when the server closes the connection.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// use kvs::{AsyncKvsClient, Result};
+/// async fn try_main() -> Result<()> {
+/// let mut client = AsyncKvsClient::connect("127.0.0.1:4000".parse().unwrap()).await?;
+/// client.set(b"key1", b"value1").await?;
+/// assert_eq!(client.get(b"key1").await?, Some(b"value1".to_vec()));
+/// Ok(())
+/// }
+/// ```
+pub struct AsyncKvsClient {
+    addr: SocketAddr,
+    options: KvsClientOptions,
+    conn: Option<Connection>,
+}
+
+impl AsyncKvsClient {
+    /// Connects to the server at `addr` with the default options.
+    pub async fn connect(addr: SocketAddr) -> Result<AsyncKvsClient> {
+        AsyncKvsClient::connect_with_options(addr, KvsClientOptions::default()).await
+    }
+
+    /// Connects to the server at `addr` with the given options.
+    pub async fn connect_with_options(
+        addr: SocketAddr,
+        options: KvsClientOptions,
+    ) -> Result<AsyncKvsClient> {
+        let conn = Connection::open(addr, &options).await?;
+        Ok(AsyncKvsClient {
+            addr,
+            options,
+            conn: Some(conn),
+        })
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist.
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        into_value(self.request(KvsRequest::Get { key: key.to_vec() }).await?)
+    }
+
+    /// Sets the value of a key.
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set_expiring(key, value, None).await
+    }
+
+    /// Sets the value of a key, which expires after `ttl` rounded down to seconds.
+    pub async fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.set_expiring(key, value, Some(ttl.as_secs())).await
+    }
+
+    async fn set_expiring(&mut self, key: &[u8], value: &[u8], ttl: Option<u64>) -> Result<()> {
+        let request = KvsRequest::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl,
+        };
+        into_set(self.request(request).await?)
+    }
+
+    /// Removes a key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub async fn remove(&mut self, key: &[u8]) -> Result<()> {
+        into_removed(
+            self.request(KvsRequest::Remove { key: key.to_vec() })
+                .await?,
+        )
+    }
+
+    /// Returns the pairs whose keys are in `[start, end)` in key order, at most
+    /// `limit` of them. A missing bound leaves that side of the range open.
+    pub async fn scan(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = KvsRequest::Scan {
+            start: start.map(<[u8]>::to_vec),
+            end: end.map(<[u8]>::to_vec),
+            limit,
+        };
+        into_entries(self.request(request).await?)
+    }
+
+    /// Returns the pairs whose keys start with `prefix` in key order, at most `limit`
+    /// of them.
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = KvsRequest::ScanPrefix {
+            prefix: prefix.to_vec(),
+            limit,
+        };
+        into_entries(self.request(request).await?)
+    }
+
+    /// Applies every write of `batch` as a unit.
+    pub async fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        into_batch_applied(self.request(KvsRequest::Batch { batch }).await?)
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, but only if its current
+    /// value is `expected`. See `KvsEngine::compare_and_swap`.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let request = KvsRequest::Cas {
+            key: key.to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            new: new.map(<[u8]>::to_vec),
+        };
+        into_cas(self.request(request).await?)
+    }
+
+    /// Sends all `requests` before reading their responses, which come back in the
+    /// same order. See `KvsClient::pipeline`.
+    pub async fn pipeline(&mut self, requests: &[KvsRequest]) -> Result<Vec<KvsResponse>> {
+        let mut responses = Vec::with_capacity(requests.len());
+        if !requests.iter().all(KvsRequest::is_idempotent) {
+            if let Some(conn) = &mut self.conn {
+                if !conn.is_healthy().await {
+                    self.conn = None;
+                }
+            }
+        }
+        loop {
+            let fresh = self.conn.is_none();
+            if fresh {
+                self.conn = Some(Connection::open(self.addr, &self.options).await?);
+            }
+            let conn = self.conn.as_mut().unwrap();
+            let answered = responses.len();
+            match conn
+                .exchange(
+                    &requests[answered..],
+                    &mut responses,
+                    self.options.read_timeout,
+                )
+                .await
+            {
+                Ok(()) => return Ok(responses),
+                Err(e) => {
+                    self.conn = None;
+                    let gone = fresh && responses.len() == answered;
+                    if gone || !can_resend(&e, &requests[responses.len()..]) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends one request and turns an error response into an error.
+    async fn request(&mut self, request: KvsRequest) -> Result<KvsResponse> {
+        into_result(self.pipeline(&[request]).await?.pop().unwrap())
+    }
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    async fn open(addr: SocketAddr, options: &KvsClientOptions) -> Result<Connection> {
+        let stream = match options.connect_timeout {
+            Some(timeout) => time::timeout(timeout, TcpStream::connect(addr))
+                .await
+                .map_err(timed_out)??,
+            None => TcpStream::connect(addr).await?,
+        };
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Returns true if the connection is open and has no unread data. Like
+    /// `KvsClient::is_healthy`, it does not wait for the server.
+    async fn is_healthy(&mut self) -> bool {
+        if !self.stream.buffer().is_empty() {
+            return false;
+        }
+        // The timeout only polls the peek once: it is pending on an open connection,
+        // while a closed one reads as end of file right away.
+        let mut buf = [0; 1];
+        let peek = self.stream.get_mut().peek(&mut buf);
+        time::timeout(Duration::from_secs(0), peek).await.is_err()
+    }
+
+    /// Writes `requests` in one go and appends their responses to `responses`.
+    async fn exchange(
+        &mut self,
+        requests: &[KvsRequest],
+        responses: &mut Vec<KvsResponse>,
+        read_timeout: Option<Duration>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        for request in requests {
+            write_frame(&mut buf, request)?;
+        }
+        self.stream.get_mut().write_all(&buf).await?;
+        for _ in requests {
+            let response = match read_timeout {
+                Some(timeout) => time::timeout(timeout, read_next_frame_async(&mut self.stream))
+                    .await
+                    .map_err(timed_out)??,
+                None => read_next_frame_async(&mut self.stream).await?,
+            };
+            match response {
+                Some(response) => responses.push(response),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed by the server",
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn timed_out(err: time::Elapsed) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, err)
+}
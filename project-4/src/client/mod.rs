@@ -0,0 +1,361 @@
+use crate::frame::read_next_frame;
+use crate::{CompareAndSwapError, KvsError, KvsRequest, KvsResponse, Result, WriteBatch};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+pub use self::async_client::AsyncKvsClient;
+pub use self::pool::{KvsClientPool, KvsClientPoolOptions, PooledClient};
+
+#[cfg(feature = "async")]
+mod async_client;
+mod pool;
+
+/// A client of `KvsServer`.
+///
+/// Requests share one connection, which is opened again when the server closes it
+/// for being idle or after its request cap.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// use kvs::{KvsClient, Result};
+/// fn try_main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000".parse().unwrap())?;
+/// client.set(b"key1", b"value1")?;
+/// assert_eq!(client.get(b"key1")?, Some(b"value1".to_vec()));
+/// Ok(())
+/// }
+/// ```
+pub struct KvsClient {
+    addr: SocketAddr,
+    options: KvsClientOptions,
+    conn: Option<Connection>,
+}
+
+/// Options used to connect a `KvsClient`.
+#[derive(Debug, Clone, Default)]
+pub struct KvsClientOptions {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+}
+
+impl KvsClientOptions {
+    /// Creates options with every setting at its default.
+    pub fn new() -> KvsClientOptions {
+        KvsClientOptions::default()
+    }
+
+    /// Sets how long connecting may take. Defaults to the operating system's limit.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> KvsClientOptions {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sets how long to wait for a response. Defaults to waiting forever.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> KvsClientOptions {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Connects a `KvsClient` to `addr` with these options.
+    pub fn connect(self, addr: SocketAddr) -> Result<KvsClient> {
+        KvsClient::connect_with_options(addr, self)
+    }
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr` with the default options.
+    pub fn connect(addr: SocketAddr) -> Result<KvsClient> {
+        KvsClient::connect_with_options(addr, KvsClientOptions::default())
+    }
+
+    /// Connects to the server at `addr` with the given options.
+    pub fn connect_with_options(addr: SocketAddr, options: KvsClientOptions) -> Result<KvsClient> {
+        let conn = Connection::open(addr, &options)?;
+        Ok(KvsClient {
+            addr,
+            options,
+            conn: Some(conn),
+        })
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        into_value(self.request(KvsRequest::Get { key: key.to_vec() })?)
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set_expiring(key, value, None)
+    }
+
+    /// Sets the value of a key, which expires after `ttl` rounded down to seconds.
+    pub fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.set_expiring(key, value, Some(ttl.as_secs()))
+    }
+
+    fn set_expiring(&mut self, key: &[u8], value: &[u8], ttl: Option<u64>) -> Result<()> {
+        let request = KvsRequest::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl,
+        };
+        into_set(self.request(request)?)
+    }
+
+    /// Removes a key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        into_removed(self.request(KvsRequest::Remove { key: key.to_vec() })?)
+    }
+
+    /// Returns the pairs whose keys are in `[start, end)` in key order, at most
+    /// `limit` of them. A missing bound leaves that side of the range open.
+    pub fn scan(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = KvsRequest::Scan {
+            start: start.map(<[u8]>::to_vec),
+            end: end.map(<[u8]>::to_vec),
+            limit,
+        };
+        into_entries(self.request(request)?)
+    }
+
+    /// Returns the pairs whose keys start with `prefix` in key order, at most `limit`
+    /// of them.
+    pub fn scan_prefix(
+        &mut self,
+        prefix: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = KvsRequest::ScanPrefix {
+            prefix: prefix.to_vec(),
+            limit,
+        };
+        into_entries(self.request(request)?)
+    }
+
+    /// Applies every write of `batch` as a unit.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        into_batch_applied(self.request(KvsRequest::Batch { batch })?)
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, but only if its current
+    /// value is `expected`. See `KvsEngine::compare_and_swap`.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let request = KvsRequest::Cas {
+            key: key.to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            new: new.map(<[u8]>::to_vec),
+        };
+        into_cas(self.request(request)?)
+    }
+
+    /// Sends all `requests` before reading their responses, which come back in the
+    /// same order.
+    ///
+    /// The server answers while the requests are still being written, so very long
+    /// pipelines should be split to keep the unread responses within the socket
+    /// buffers.
+    ///
+    /// When the connection is closed before every response arrived, the unanswered
+    /// requests are resent on a new connection if they are all idempotent. Otherwise
+    /// the error is returned, since the server may have applied them.
+    pub fn pipeline(&mut self, requests: &[KvsRequest]) -> Result<Vec<KvsResponse>> {
+        let mut responses = Vec::with_capacity(requests.len());
+        // A connection the server already closed for being idle would fail requests
+        // that cannot be resent.
+        if !requests.iter().all(KvsRequest::is_idempotent) && !self.is_healthy() {
+            self.conn = None;
+        }
+        loop {
+            let fresh = self.conn.is_none();
+            if fresh {
+                self.conn = Some(Connection::open(self.addr, &self.options)?);
+            }
+            let conn = self.conn.as_mut().unwrap();
+            let answered = responses.len();
+            match conn.exchange(&requests[answered..], &mut responses) {
+                Ok(()) => return Ok(responses),
+                Err(e) => {
+                    self.conn = None;
+                    // A new connection closing before answering anything means the
+                    // server is going away.
+                    let gone = fresh && responses.len() == answered;
+                    if gone || !can_resend(&e, &requests[responses.len()..]) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns true if the connection is open and has no unread data.
+    ///
+    /// This does not wait for the server, so it only notices a connection the server
+    /// has already closed.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.conn.as_ref().map_or(false, Connection::is_healthy)
+    }
+
+    /// Sends one request and turns an error response into an error.
+    fn request(&mut self, request: KvsRequest) -> Result<KvsResponse> {
+        into_result(self.pipeline(&[request])?.pop().unwrap())
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn open(addr: SocketAddr, options: &KvsClientOptions) -> Result<Connection> {
+        let stream = match options.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_read_timeout(options.read_timeout)?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return false;
+        }
+        let stream = self.writer.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        // Reading would block on an open connection, while a closed one reads as
+        // end of file right away.
+        let mut buf = [0; 1];
+        let healthy = match stream.peek(&mut buf) {
+            Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+        stream.set_nonblocking(false).is_ok() && healthy
+    }
+
+    /// Writes `requests` and appends their responses to `responses`.
+    fn exchange(
+        &mut self,
+        requests: &[KvsRequest],
+        responses: &mut Vec<KvsResponse>,
+    ) -> Result<()> {
+        for request in requests {
+            request.write_to(&mut self.writer)?;
+        }
+        self.writer.flush()?;
+        for _ in requests {
+            match read_next_frame(&mut self.reader)? {
+                Some(response) => responses.push(response),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed by the server",
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if `unanswered` can be sent again after `err` ended the exchange.
+///
+/// A server that is shut down or crashes may close a connection after applying
+/// requests it has not answered yet, so only requests that can be applied twice are
+/// resent.
+fn can_resend(err: &KvsError, unanswered: &[KvsRequest]) -> bool {
+    is_closed(err) && unanswered.iter().all(KvsRequest::is_idempotent)
+}
+
+/// Returns true if `err` means the server closed the connection.
+fn is_closed(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::BrokenPipe
+        ),
+        _ => false,
+    }
+}
+
+/// Turns an error response into an error.
+fn into_result(response: KvsResponse) -> Result<KvsResponse> {
+    match response {
+        KvsResponse::Err { code, message } => Err(KvsError::ServerError { code, message }),
+        response => Ok(response),
+    }
+}
+
+fn into_value(response: KvsResponse) -> Result<Option<Vec<u8>>> {
+    match response {
+        KvsResponse::Value(value) => Ok(Some(value)),
+        KvsResponse::NotFound => Ok(None),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn into_set(response: KvsResponse) -> Result<()> {
+    match response {
+        KvsResponse::Set => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn into_removed(response: KvsResponse) -> Result<()> {
+    match response {
+        KvsResponse::Removed => Ok(()),
+        KvsResponse::NotFound => Err(KvsError::KeyNotFound),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn into_entries(response: KvsResponse) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    match response {
+        KvsResponse::Entries(entries) => Ok(entries),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn into_batch_applied(response: KvsResponse) -> Result<()> {
+    match response {
+        KvsResponse::BatchApplied => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn into_cas(response: KvsResponse) -> Result<std::result::Result<(), CompareAndSwapError>> {
+    match response {
+        KvsResponse::Cas { applied: true, .. } => Ok(Ok(())),
+        KvsResponse::Cas { current, .. } => Ok(Err(CompareAndSwapError { current })),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: KvsResponse) -> KvsError {
+    KvsError::Protocol(format!("Unexpected response {:?}", response))
+}
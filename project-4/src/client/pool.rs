@@ -0,0 +1,226 @@
+use super::{KvsClient, KvsClientOptions};
+use crate::Result;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A pool of `KvsClient` connections shared between threads.
+///
+/// Cloning the pool is cheap and every clone hands out connections from the same
+/// set. A connection is checked out with `get` and goes back to the pool when the
+/// returned `PooledClient` is dropped.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// use kvs::{KvsClientPoolOptions, Result};
+/// use std::time::Duration;
+/// fn try_main() -> Result<()> {
+/// let pool = KvsClientPoolOptions::new()
+///     .min_idle(2)
+///     .max_lifetime(Duration::from_secs(600))
+///     .connect("127.0.0.1:4000".parse().unwrap())?;
+/// pool.get()?.set(b"key1", b"value1")?;
+/// Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addr: SocketAddr,
+    options: KvsClientPoolOptions,
+    /// Connections not checked out, the most recently returned last.
+    idle: Mutex<VecDeque<IdleClient>>,
+}
+
+struct IdleClient {
+    client: KvsClient,
+    created: Instant,
+}
+
+/// Options used to create a `KvsClientPool`.
+#[derive(Debug, Clone)]
+pub struct KvsClientPoolOptions {
+    client_options: KvsClientOptions,
+    min_idle: usize,
+    max_idle: usize,
+    max_lifetime: Option<Duration>,
+}
+
+impl Default for KvsClientPoolOptions {
+    fn default() -> KvsClientPoolOptions {
+        KvsClientPoolOptions {
+            client_options: KvsClientOptions::default(),
+            min_idle: 0,
+            max_idle: 8,
+            max_lifetime: None,
+        }
+    }
+}
+
+impl KvsClientPoolOptions {
+    /// Creates options with every setting at its default.
+    pub fn new() -> KvsClientPoolOptions {
+        KvsClientPoolOptions::default()
+    }
+
+    /// Sets the options every connection of the pool is opened with.
+    pub fn client_options(mut self, client_options: KvsClientOptions) -> KvsClientPoolOptions {
+        self.client_options = client_options;
+        self
+    }
+
+    /// Sets how many idle connections the pool keeps open. They are opened when the
+    /// pool is created, and checking a connection out opens new ones while fewer are
+    /// left. Defaults to 0.
+    pub fn min_idle(mut self, min_idle: usize) -> KvsClientPoolOptions {
+        self.min_idle = min_idle;
+        self
+    }
+
+    /// Sets how many returned connections are kept for reuse; the others are closed.
+    /// Defaults to 8.
+    pub fn max_idle(mut self, max_idle: usize) -> KvsClientPoolOptions {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Sets how long a connection is used before it is closed and replaced.
+    /// Defaults to no limit.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> KvsClientPoolOptions {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Creates a pool of connections to `addr` with these options.
+    pub fn connect(self, addr: SocketAddr) -> Result<KvsClientPool> {
+        KvsClientPool::connect_with_options(addr, self)
+    }
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to `addr` with the default options.
+    pub fn connect(addr: SocketAddr) -> Result<KvsClientPool> {
+        KvsClientPool::connect_with_options(addr, KvsClientPoolOptions::default())
+    }
+
+    /// Creates a pool of connections to `addr` and opens `min_idle` of them.
+    pub fn connect_with_options(
+        addr: SocketAddr,
+        options: KvsClientPoolOptions,
+    ) -> Result<KvsClientPool> {
+        let inner = PoolInner {
+            addr,
+            options,
+            idle: Mutex::new(VecDeque::new()),
+        };
+        inner.fill()?;
+        Ok(KvsClientPool {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Checks out a connection, opening one if no idle connection is usable.
+    ///
+    /// Idle connections past their lifetime or closed by the server are dropped
+    /// instead of being handed out. The idle connections are then topped up to
+    /// `min_idle`.
+    pub fn get(&self) -> Result<PooledClient> {
+        let client = loop {
+            let idle = self.inner.idle.lock().unwrap().pop_back();
+            match idle {
+                Some(idle) if !self.inner.expired(&idle) && idle.client.is_healthy() => break idle,
+                Some(_) => continue,
+                None => break self.inner.open()?,
+            }
+        };
+        // The caller has a usable connection either way, and the next checkout tries
+        // again.
+        let _ = self.inner.fill();
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.inner.clone(),
+        })
+    }
+
+    /// Returns the number of connections waiting in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+}
+
+impl PoolInner {
+    fn open(&self) -> Result<IdleClient> {
+        let client =
+            KvsClient::connect_with_options(self.addr, self.options.client_options.clone())?;
+        Ok(IdleClient {
+            client,
+            created: Instant::now(),
+        })
+    }
+
+    /// Opens connections until `min_idle` of them are idle.
+    ///
+    /// They are opened without holding the lock, so other threads can check
+    /// connections out meanwhile.
+    fn fill(&self) -> Result<()> {
+        let missing = self
+            .options
+            .min_idle
+            .saturating_sub(self.idle.lock().unwrap().len());
+        for _ in 0..missing {
+            let client = self.open()?;
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() >= self.options.min_idle {
+                break;
+            }
+            idle.push_back(client);
+        }
+        Ok(())
+    }
+
+    fn expired(&self, idle: &IdleClient) -> bool {
+        self.options
+            .max_lifetime
+            .map_or(false, |max_lifetime| idle.created.elapsed() >= max_lifetime)
+    }
+}
+
+/// A connection checked out of a `KvsClientPool`, returned to it when dropped.
+pub struct PooledClient {
+    client: Option<IdleClient>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        &self.client.as_ref().unwrap().client
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        &mut self.client.as_mut().unwrap().client
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let client = self.client.take().unwrap();
+        // A failed request leaves the client disconnected, so it is not worth keeping.
+        if self.pool.expired(&client) || !client.client.is_healthy() {
+            return;
+        }
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < self.pool.options.max_idle {
+            idle.push_back(client);
+        }
+    }
+}
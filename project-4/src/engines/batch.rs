@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes applied atomically by `KvsEngine::apply_batch`.
+///
+/// Either every write of the batch is persisted or none is, even across a crash.
+///
+/// Example:
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+/// use std::env::current_dir;
+/// fn try_main() -> Result<()> {
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1".to_owned(), "value1".to_owned());
+/// batch.remove("key2".to_owned());
+/// store.apply_batch(batch)?;
+/// Ok(())
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    /// Sets `key` to `value`.
+    Set {
+        /// key
+        key: Vec<u8>,
+        /// value
+        value: Vec<u8>,
+    },
+    /// Removes `key`. Removing a key that does not exist is not an error.
+    Remove {
+        /// key
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting `key` to `value` to the batch.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Adds removing `key` to the batch.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the writes in the order they were added.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
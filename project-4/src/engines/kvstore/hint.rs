@@ -0,0 +1,133 @@
+//! Hint files let `KvStore::open` rebuild the index of a compacted generation without
+//! reading its values.
+//!
+//! A hint file is written next to each compacted log and lists every entry copied into
+//! it. Its layout is a header, the entries, and a CRC32 of everything before it:
+//!
+//! ```text
+//! | magic (2) | version (1) | entry* | crc32 (4) |
+//!
+//! entry: | key length (4) | key | gen (8) | position (8) | length (8) | expires at (8) |
+//! ```
+//!
+//! Integers are little-endian. An expiry time of 0 means the key does not expire.
+
+use super::CommandPosition;
+use crate::{KvsError, Result};
+use crc32fast::Hasher;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 2] = *b"KH";
+const VERSION: u8 = 2;
+
+/// Writes the hint file at `path` for the given entries.
+///
+/// The file is written under a temporary name and renamed into place, so a hint file
+/// is either complete or absent.
+pub(super) fn write_hint(
+    path: &Path,
+    entries: &[(Vec<u8>, CommandPosition)],
+    durable: bool,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    for (key, cmd_pos) in entries {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&cmd_pos.gen.to_le_bytes());
+        buf.extend_from_slice(&cmd_pos.position.to_le_bytes());
+        buf.extend_from_slice(&cmd_pos.length.to_le_bytes());
+        buf.extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+    }
+    let crc = checksum(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let tmp_path = path.with_extension("hint.compacting");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    if durable {
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the entries of the hint file at `path`.
+///
+/// Fails if the file is truncated, fails its checksum, or does not match generation
+/// `gen` whose log is `log_len` bytes long.
+pub(super) fn read_hint(
+    path: &Path,
+    gen: u64,
+    log_len: u64,
+) -> Result<Vec<(Vec<u8>, CommandPosition)>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < MAGIC.len() + 1 + 4 {
+        return Err(KvsError::Other("Hint file too short".to_owned()));
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if checksum(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(KvsError::Other("Hint file checksum mismatch".to_owned()));
+    }
+    if body[..2] != MAGIC || body[2] != VERSION {
+        return Err(KvsError::Other("Unsupported hint file".to_owned()));
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &body[3..];
+    while !rest.is_empty() {
+        let key_len = take_u32(&mut rest)? as usize;
+        if rest.len() < key_len {
+            return Err(KvsError::Other("Hint entry out of bounds".to_owned()));
+        }
+        let key = rest[..key_len].to_vec();
+        rest = &rest[key_len..];
+        let cmd_pos = CommandPosition {
+            gen: take_u64(&mut rest)?,
+            position: take_u64(&mut rest)?,
+            length: take_u64(&mut rest)?,
+            expires_at: match take_u64(&mut rest)? {
+                0 => None,
+                expires_at => Some(expires_at),
+            },
+        };
+        if cmd_pos.gen != gen || cmd_pos.position + cmd_pos.length > log_len {
+            return Err(KvsError::Other(
+                "Hint entry points outside of the log".to_owned(),
+            ));
+        }
+        entries.push((key, cmd_pos));
+    }
+    Ok(entries)
+}
+
+fn take_u32(rest: &mut &[u8]) -> Result<u32> {
+    let mut word = [0; 4];
+    take(rest, &mut word)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+fn take_u64(rest: &mut &[u8]) -> Result<u64> {
+    let mut word = [0; 8];
+    take(rest, &mut word)?;
+    Ok(u64::from_le_bytes(word))
+}
+
+fn take(rest: &mut &[u8], out: &mut [u8]) -> Result<()> {
+    if rest.len() < out.len() {
+        return Err(KvsError::Other("Hint entry out of bounds".to_owned()));
+    }
+    out.copy_from_slice(&rest[..out.len()]);
+    *rest = &rest[out.len()..];
+    Ok(())
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
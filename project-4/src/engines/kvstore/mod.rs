@@ -0,0 +1,1277 @@
+use self::hint::{read_hint, write_hint};
+use self::record::{is_legacy_log, read_record, upgrade_legacy_log, Command};
+use crate::engines::{
+    expiry_after, now_millis, BatchOp, CompareAndSwapError, KvsEngine, ScanBytesIter, WriteBatch,
+};
+use crate::{KvsError, Result};
+use crossbeam_skiplist::SkipMap;
+use log::{error, info, warn};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ffi,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+};
+
+pub use self::options::{CompactionPolicy, KvStoreOptions, SyncPolicy};
+
+mod hint;
+mod options;
+mod record;
+
+///A key-value Store of bytes
+///
+/// Example:
+///
+/// ```rust
+/// use kvs::{Result, KvStore, KvsEngine};
+/// use std::env::current_dir;
+/// fn try_main() -> Result<()> {
+/// let mut store = KvStore::open(current_dir()?)?;
+/// store.set("key1".to_owned(), "value1".to_owned());
+/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+/// Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct KvStore {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    queue: Arc<Mutex<Vec<PendingWrite>>>,
+    compactor: Arc<Compactor>,
+    index: Arc<SkipMap<Vec<u8>, CommandPosition>>,
+    reader: KvStoreReader,
+    path: Arc<PathBuf>,
+}
+
+impl KvStore {
+    ///Open a KvStore
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    ///Open a KvStore with the given options
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        // Remove compaction output left behind by a crash; the generations it was
+        // copied from are still complete.
+        for entry in fs::read_dir(&path)? {
+            let entry_path = entry?.path();
+            if entry_path.extension() == Some("compacting".as_ref()) {
+                fs::remove_file(entry_path)?;
+            }
+        }
+
+        let mut readers = BTreeMap::new();
+        let mut index: SkipMap<Vec<u8>, CommandPosition> = SkipMap::new();
+        let gen_list = sort_gen_list(&path)?;
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+
+        // Only the newest generation can have been cut off by a crash mid-write.
+        let active_gen = gen_list.last().cloned();
+        let mut uncompacted_size = 0;
+        for gen in gen_list {
+            let log_path = log_path(&path, gen);
+            let recover = Some(gen) == active_gen;
+            if is_legacy_log(&log_path)? {
+                info!("Upgrading {} to the binary log format", log_path.display());
+                upgrade_legacy_log(&log_path, gen, recover)?;
+            }
+            let file = OpenOptions::new().read(true).open(&log_path)?;
+            let mut reader = BufReader::new(file);
+
+            let hint_path = hint_path(&path, gen);
+            if hint_path.exists() {
+                let log_len = reader.get_ref().metadata()?.len();
+                match read_hint(&hint_path, gen, log_len) {
+                    Ok(entries) => {
+                        let now = now_millis();
+                        for (key, cmd_pos) in entries {
+                            if let Some(old_cmd) = index.get(&key) {
+                                uncompacted_size += old_cmd.value().length;
+                            }
+                            if cmd_pos.is_expired(now) {
+                                index.remove(&key);
+                                uncompacted_size += cmd_pos.length;
+                            } else {
+                                index.insert(key, cmd_pos);
+                            }
+                        }
+                        readers.insert(gen, reader);
+                        continue;
+                    }
+                    Err(e) => warn!("Ignoring hint file {}: {}", hint_path.display(), e),
+                }
+            }
+
+            let replay = build_index(gen, &mut reader, &mut index)?;
+            if let Some(err) = replay.error {
+                if !recover {
+                    error!("Corrupt record in {}: {}", log_path.display(), err);
+                    return Err(KvsError::Corruption {
+                        gen,
+                        offset: replay.valid_len,
+                    });
+                }
+                truncate_log(&log_path, replay.valid_len, &err)?;
+            }
+            uncompacted_size += replay.uncompacted_size;
+            readers.insert(gen, reader);
+        }
+        let live_size = index.iter().map(|entry| entry.value().length).sum();
+        let generations = readers.len() + 1;
+        let writer = new_log_file(&path, current_gen)?;
+        if options.sync_policy != SyncPolicy::Never {
+            sync_dir(&path)?;
+        }
+
+        let index = Arc::new(index);
+        let path = Arc::new(path.into());
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+        };
+
+        let writer = KvStoreWriter {
+            reader: reader.clone(),
+            position: writer.metadata()?.len(),
+            writer,
+            current_gen,
+            uncompacted_size,
+            live_size,
+            generations,
+            sync_policy: options.sync_policy,
+            compaction_policy: options.compaction_policy,
+            unsynced: 0,
+            compacting: false,
+            sealed_gen: 0,
+            sealed_stale: 0,
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+        };
+        let writer = Arc::new(Mutex::new(writer));
+
+        if let SyncPolicy::Interval(ms) = options.sync_policy {
+            // The syncer only holds a weak reference, so it exits once the store is dropped.
+            let writer = Arc::downgrade(&writer);
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_millis(ms));
+                let writer = match writer.upgrade() {
+                    Some(writer) => writer,
+                    None => break,
+                };
+                let mut writer = writer.lock().unwrap();
+                if writer.unsynced > 0 {
+                    if let Err(e) = writer.sync() {
+                        error!("Error on syncing log: {}", e);
+                    }
+                }
+            });
+        }
+
+        Ok(KvStore {
+            path,
+            reader,
+            index,
+            writer,
+            queue: Arc::new(Mutex::new(Vec::new())),
+            compactor: Arc::new(Compactor::default()),
+        })
+    }
+
+    /// Truncates every generation at its first unreadable record.
+    ///
+    /// This salvages a store that `open` refuses because a sealed generation is
+    /// corrupt. Records following the corruption in the same generation are lost.
+    /// Returns the number of bytes dropped.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<u64> {
+        let path = path.into();
+        let mut dropped = 0;
+        for gen in sort_gen_list(&path)? {
+            let log_path = log_path(&path, gen);
+            if is_legacy_log(&log_path)? {
+                dropped += upgrade_legacy_log(&log_path, gen, true)?;
+            }
+            let mut reader = BufReader::new(File::open(&log_path)?);
+            let replay = build_index(gen, &mut reader, &mut SkipMap::new())?;
+            if let Some(err) = replay.error {
+                dropped += truncate_log(&log_path, replay.valid_len, &err)?;
+                remove_if_exists(&hint_path(&path, gen))?;
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Compacts the log now, whatever the compaction policy says.
+    ///
+    /// Returns once the compaction has finished. If one is already running, waits for
+    /// it instead of starting another.
+    pub fn compact(&self) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            if !writer.compacting {
+                let compaction = writer.seal()?;
+                self.compactor.spawn(compaction, Arc::clone(&self.writer));
+            }
+        }
+        self.compactor.wait()
+    }
+
+    /// Returns the current space usage of the store.
+    pub fn stats(&self) -> KvStoreStats {
+        self.writer.lock().unwrap().stats()
+    }
+
+    /// Queues `commands` and waits until they have been committed to the log.
+    ///
+    /// Writers append to the commit queue and then contend for the writer lock.
+    /// Whoever gets it commits everything queued so far with a single write and
+    /// fsync, so writers arriving during a commit share the next one.
+    fn write(&self, commands: Vec<Command>, kind: WriteKind) -> WriteResult {
+        let done = Arc::new(Mutex::new(None));
+        self.queue.lock().unwrap().push(PendingWrite {
+            commands,
+            kind,
+            done: Arc::clone(&done),
+        });
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(result) = done.lock().unwrap().take() {
+            // An earlier leader committed our write while we waited for the lock.
+            return result;
+        }
+        let batch = mem::replace(&mut *self.queue.lock().unwrap(), Vec::new());
+        writer.commit(batch);
+        if !writer.compacting && writer.compaction_due() {
+            match writer.seal() {
+                Ok(compaction) => self.compactor.spawn(compaction, Arc::clone(&self.writer)),
+                Err(e) => error!("Error on starting compaction: {}", e),
+            }
+        }
+        let result = done.lock().unwrap().take();
+        result.expect("queued write was not committed")
+    }
+}
+
+impl KvsEngine for KvStore {
+    ///Set a key-value pair.
+    ///
+    /// If the key already exists, value will be overwritten.
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let command = Command::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at: None,
+        };
+        self.write(vec![command], WriteKind::Single).map(|_| ())
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let command = Command::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at: Some(expiry_after(ttl)),
+        };
+        self.write(vec![command], WriteKind::Single).map(|_| ())
+    }
+    ///Get the value of a key.
+    ///
+    /// Return NONE if the key does not exist.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let cmd_pos = match self.index.get(key) {
+                Some(entry) => *entry.value(),
+                None => return Ok(None),
+            };
+            if cmd_pos.is_expired(now_millis()) {
+                return Ok(None);
+            }
+            match self.reader.read_command(cmd_pos) {
+                Ok(Command::Set { value, .. }) => return Ok(Some(value)),
+                Ok(_) => return Err(corruption(cmd_pos)),
+                // Compaction may have moved the entry and deleted the generation it
+                // was in between the index lookup and the read.
+                Err(_) if self.index.get(key).map(|entry| *entry.value()) != Some(cmd_pos) => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    ///Remove the given key.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let command = Command::Remove { key: key.to_vec() };
+        self.write(vec![command], WriteKind::Single).map(|_| ())
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let commands = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                },
+                BatchOp::Remove { key } => Command::Remove { key },
+            })
+            .collect();
+        self.write(commands, WriteKind::Batch).map(|_| ())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let key = key.to_vec();
+        let command = match new {
+            Some(value) => Command::Set {
+                key,
+                value: value.to_vec(),
+                expires_at: None,
+            },
+            None => Command::Remove { key },
+        };
+        let expected = expected.map(<[u8]>::to_vec);
+        self.write(vec![command], WriteKind::CompareAndSwap { expected })
+    }
+
+    fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        Ok(Box::new(Scan {
+            store: self.clone(),
+            next: cloned_bound(range.start_bound()),
+            end: cloned_bound(range.end_bound()),
+            prefix: None,
+            remaining: limit,
+        }))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8], limit: Option<usize>) -> Result<ScanBytesIter> {
+        Ok(Box::new(Scan {
+            store: self.clone(),
+            next: Bound::Included(prefix.to_vec()),
+            end: Bound::Unbounded,
+            prefix: Some(prefix.to_vec()),
+            remaining: limit,
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()?;
+        Ok(())
+    }
+}
+
+/// Iterator over a key range of a `KvStore`.
+///
+/// It looks up the key after the last one returned on every step instead of holding a
+/// cursor into the index, so it sees the index as it is at each step and reads every
+/// value through `get_bytes`.
+struct Scan {
+    store: KvStore,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    prefix: Option<Vec<u8>>,
+    remaining: Option<usize>,
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.remaining == Some(0) {
+                return None;
+            }
+            let key = self
+                .store
+                .index
+                .range((self.next.clone(), self.end.clone()))
+                .next()?
+                .key()
+                .clone();
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    return None;
+                }
+            }
+            self.next = Bound::Excluded(key.clone());
+            match self.store.get_bytes(&key) {
+                Ok(Some(value)) => {
+                    self.remaining = self.remaining.map(|n| n - 1);
+                    return Some(Ok((key, value)));
+                }
+                // Removed since it was found in the index.
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn command_key(command: &Command) -> &[u8] {
+    match command {
+        Command::Set { key, .. } | Command::Remove { key } => key,
+        _ => unreachable!("batch markers are never queued"),
+    }
+}
+
+fn cloned_bound(bound: Bound<&Vec<u8>>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn new_log_file(path: &Path, gen: u64) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .append(true)
+        .create(true)
+        .open(log_path(&path, gen))?;
+    Ok(file)
+}
+
+struct KvStoreReader {
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+    /// Generations below this have been compacted away and deleted.
+    safe_point: Arc<AtomicU64>,
+    path: Arc<PathBuf>,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            readers: RefCell::new(BTreeMap::new()),
+            safe_point: Arc::clone(&self.safe_point),
+            path: Arc::clone(&self.path),
+        }
+    }
+}
+
+impl KvStoreReader {
+    /// Close file handles of generations deleted by compaction.
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        while let Some(&gen) = readers.keys().next() {
+            if gen >= safe_point {
+                break;
+            }
+            readers.remove(&gen);
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    fn read_and<F, R>(&self, cmd_pos: CommandPosition, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReader<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        // We don't use entry API here because we want the errors to be propogated.
+        if !readers.contains_key(&cmd_pos.gen) {
+            let reader = BufReader::new(File::open(log_path(&self.path, cmd_pos.gen))?);
+            readers.insert(cmd_pos.gen, reader);
+        }
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        reader.seek(SeekFrom::Start(cmd_pos.position))?;
+        let cmd_reader = reader.take(cmd_pos.length);
+        f(cmd_reader)
+    }
+
+    // Read the log file at the given `CommandPos` and decode it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPosition) -> Result<Command> {
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            match read_record(&mut cmd_reader) {
+                Ok(Some((command, _))) => Ok(command),
+                Ok(None) => Err(corruption(cmd_pos)),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::UnexpectedEof
+                        || e.kind() == io::ErrorKind::InvalidData =>
+                {
+                    Err(corruption(cmd_pos))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
+
+/// A write waiting in the commit queue.
+struct PendingWrite {
+    commands: Vec<Command>,
+    kind: WriteKind,
+    /// Filled in with the outcome once a leader has committed the write.
+    done: Arc<Mutex<Option<WriteResult>>>,
+}
+
+/// How the commands of a `PendingWrite` are committed.
+enum WriteKind {
+    /// A single `set` or `remove`. Removing a missing key fails.
+    Single,
+    /// The commands of a `WriteBatch`, framed by batch markers.
+    Batch,
+    /// A single command written only if the key currently holds `expected`.
+    CompareAndSwap { expected: Option<Vec<u8>> },
+}
+
+/// Outcome of a `PendingWrite`. Only a compare-and-swap can end up not applied.
+type WriteResult = Result<std::result::Result<(), CompareAndSwapError>>;
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: File,
+    /// Length of the active log, where the next record will be written.
+    position: u64,
+    uncompacted_size: u64,
+    /// Total length of the records the index points to.
+    live_size: u64,
+    /// Number of log files, including the active one.
+    generations: usize,
+    current_gen: u64,
+    sync_policy: SyncPolicy,
+    compaction_policy: CompactionPolicy,
+    /// Number of writes since the last fsync.
+    unsynced: u32,
+    /// Whether a background compaction is running.
+    compacting: bool,
+    /// Generations below this one were sealed for compaction. Their records are
+    /// deleted by it, so overwriting one adds no stale bytes.
+    sealed_gen: u64,
+    /// Stale bytes of the sealed generations, counted again if the compaction fails.
+    sealed_stale: u64,
+    path: Arc<PathBuf>,
+    index: Arc<SkipMap<Vec<u8>, CommandPosition>>,
+}
+
+impl KvStoreWriter {
+    fn stats(&self) -> KvStoreStats {
+        KvStoreStats {
+            stale_bytes: self.uncompacted_size,
+            live_bytes: self.live_size,
+            generations: self.generations,
+            compacting: self.compacting,
+        }
+    }
+
+    fn compaction_due(&self) -> bool {
+        match self.compaction_policy {
+            CompactionPolicy::StaleBytes(threshold) => self.uncompacted_size > threshold,
+            CompactionPolicy::StaleRatio(ratio) => {
+                self.uncompacted_size > 0
+                    && self.uncompacted_size as f64 > ratio * self.live_size as f64
+            }
+            // A compaction always leaves two generations behind: the compacted one
+            // and the active one.
+            CompactionPolicy::MaxGenerations(max) => self.generations > max.max(2),
+            CompactionPolicy::Manual => false,
+        }
+    }
+
+    /// Seals the active generation and returns the job compacting everything before
+    /// it.
+    ///
+    /// New writes go to a fresh generation while the job runs in the background.
+    fn seal(&mut self) -> Result<Compaction> {
+        info!(
+            "Compacting log: {} stale bytes, {} live bytes, {} generations",
+            self.uncompacted_size, self.live_size, self.generations
+        );
+        let durable = self.sync_policy != SyncPolicy::Never;
+        if durable && self.unsynced > 0 {
+            self.sync()?;
+        }
+        let compact_gen = self.current_gen + 1;
+        self.current_gen += 2;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+        if durable {
+            sync_dir(&self.path)?;
+        }
+        self.position = 0;
+        self.generations += 1;
+        // Stale records in the sealed generations are reclaimed by this compaction.
+        self.sealed_stale = self.uncompacted_size;
+        self.uncompacted_size = 0;
+        self.compacting = true;
+        self.sealed_gen = compact_gen;
+
+        Ok(Compaction {
+            compact_gen,
+            durable,
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+            path: Arc::clone(&self.path),
+        })
+    }
+
+    /// Appends a batch of queued writes to the log with a single write and fsync,
+    /// then publishes them to the index and hands each writer its result.
+    fn commit(&mut self, batch: Vec<PendingWrite>) {
+        let start = self.position;
+        let mut buf = Vec::new();
+        let mut staged = Vec::with_capacity(batch.len());
+        // Values of keys written earlier in this batch, `None` for removed keys. They
+        // are not in the index yet, so checks against the current value look here first.
+        let mut pending: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+        // Bytes of batch markers, which are stale as soon as they are written.
+        let mut marker_size = 0;
+        let now = now_millis();
+        'writes: for write in batch {
+            if let WriteKind::CompareAndSwap { expected } = &write.kind {
+                let key = command_key(&write.commands[0]);
+                let current = match pending.get(key) {
+                    Some(value) => value.clone(),
+                    None => match self.read_value(key, now) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            *write.done.lock().unwrap() = Some(Err(e));
+                            continue;
+                        }
+                    },
+                };
+                if current != *expected {
+                    *write.done.lock().unwrap() = Some(Ok(Err(CompareAndSwapError { current })));
+                    continue;
+                }
+            }
+
+            // Encoded before the write touches `pending`, so a record too large for the
+            // log fails its own write alone.
+            let records = match write
+                .commands
+                .iter()
+                .map(Command::encode)
+                .collect::<Result<Vec<_>>>()
+            {
+                Ok(records) => records,
+                Err(e) => {
+                    *write.done.lock().unwrap() = Some(Err(e));
+                    continue;
+                }
+            };
+            let mut commands = Vec::with_capacity(write.commands.len());
+            for (command, record) in write.commands.into_iter().zip(records) {
+                let key = command_key(&command);
+                let present = match pending.get(key) {
+                    Some(value) => value.is_some(),
+                    None => self
+                        .index
+                        .get(key)
+                        .map_or(false, |entry| !entry.value().is_expired(now)),
+                };
+                match &command {
+                    Command::Set { key, value, .. } => {
+                        pending.insert(key.clone(), Some(value.clone()));
+                    }
+                    Command::Remove { .. } if !present => match write.kind {
+                        WriteKind::Single => {
+                            *write.done.lock().unwrap() = Some(Err(KvsError::KeyNotFound));
+                            continue 'writes;
+                        }
+                        // Removing a missing key is a no-op everywhere else.
+                        _ => continue,
+                    },
+                    _ => {
+                        pending.insert(key.to_vec(), None);
+                    }
+                }
+                commands.push((command, record));
+            }
+            if commands.is_empty() {
+                *write.done.lock().unwrap() = Some(Ok(Ok(())));
+                continue;
+            }
+
+            let atomic = matches!(write.kind, WriteKind::Batch);
+            let len = commands.len() as u32;
+            if atomic {
+                let marker = encode_marker(Command::BatchBegin { len });
+                marker_size += marker.len() as u64;
+                buf.extend_from_slice(&marker);
+            }
+            let mut positions = Vec::with_capacity(commands.len());
+            for (command, record) in commands {
+                let cmd_pos = CommandPosition {
+                    gen: self.current_gen,
+                    position: start + buf.len() as u64,
+                    length: record.len() as u64,
+                    expires_at: match &command {
+                        Command::Set { expires_at, .. } => *expires_at,
+                        _ => None,
+                    },
+                };
+                buf.extend_from_slice(&record);
+                positions.push((command, cmd_pos));
+            }
+            if atomic {
+                let marker = encode_marker(Command::BatchCommit { len });
+                marker_size += marker.len() as u64;
+                buf.extend_from_slice(&marker);
+            }
+            staged.push((write.done, positions));
+        }
+        if staged.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.append(&buf, staged.len() as u32) {
+            // Drop whatever part of the batch made it to the file, so the log never
+            // holds records that were reported as failed.
+            let _ = self.writer.set_len(start);
+            for (done, _) in staged {
+                // `io::Error` is not `Clone`, so every waiter gets a copy.
+                let e = io::Error::new(e.kind(), e.to_string());
+                *done.lock().unwrap() = Some(Err(e.into()));
+            }
+            return;
+        }
+        self.position += buf.len() as u64;
+        self.uncompacted_size += marker_size;
+
+        for (done, positions) in staged {
+            for (command, cmd_pos) in positions {
+                self.apply(command, cmd_pos);
+            }
+            *done.lock().unwrap() = Some(Ok(Ok(())));
+        }
+    }
+
+    /// Reads the committed value of `key` from the log, as of `now`.
+    fn read_value(&self, key: &[u8], now: u64) -> Result<Option<Vec<u8>>> {
+        let cmd_pos = match self.index.get(key) {
+            Some(entry) if !entry.value().is_expired(now) => *entry.value(),
+            _ => return Ok(None),
+        };
+        match self.reader.read_command(cmd_pos)? {
+            Command::Set { value, .. } => Ok(Some(value)),
+            _ => Err(corruption(cmd_pos)),
+        }
+    }
+
+    /// Publishes a committed command to the index and updates the space usage.
+    fn apply(&mut self, command: Command, cmd_pos: CommandPosition) {
+        match command {
+            Command::Set { key, .. } => {
+                let old_pos = self.index.get(&key).map(|entry| *entry.value());
+                if let Some(old_pos) = old_pos {
+                    self.displace(old_pos);
+                }
+                self.live_size += cmd_pos.length;
+                self.index.insert(key, cmd_pos);
+            }
+            Command::Remove { key } => {
+                let old_pos = self.index.remove(&key).map(|entry| *entry.value());
+                if let Some(old_pos) = old_pos {
+                    self.displace(old_pos);
+                }
+                self.uncompacted_size += cmd_pos.length;
+            }
+            _ => unreachable!("batch markers are never indexed"),
+        }
+    }
+
+    /// Accounts for a record that the index no longer points to.
+    fn displace(&mut self, old_pos: CommandPosition) {
+        self.live_size -= old_pos.length;
+        // A sealed record is deleted by the compaction, which counts its copy instead
+        // if it made one.
+        if old_pos.gen >= self.sealed_gen {
+            self.uncompacted_size += old_pos.length;
+        } else {
+            self.sealed_stale += old_pos.length;
+        }
+    }
+
+    fn append(&mut self, buf: &[u8], writes: u32) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.sync_after_write(writes)
+    }
+
+    /// Fsyncs the active log if the sync policy asks for it after these writes.
+    fn sync_after_write(&mut self, writes: u32) -> io::Result<()> {
+        self.unsynced += writes;
+        let due = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(_) | SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Compaction of every generation before `compact_gen` into `compact_gen`.
+struct Compaction {
+    compact_gen: u64,
+    durable: bool,
+    reader: KvStoreReader,
+    index: Arc<SkipMap<Vec<u8>, CommandPosition>>,
+    path: Arc<PathBuf>,
+}
+
+impl Compaction {
+    fn run(self, writer: &Mutex<KvStoreWriter>) -> Result<()> {
+        let moved = self.copy();
+        let mut writer = writer.lock().unwrap();
+        let result = moved.and_then(|moved| self.switch(&mut writer, moved));
+        if let Err(ref e) = result {
+            error!("Error on compacting log: {}", e);
+            let _ = fs::remove_file(compacting_path(&self.path, self.compact_gen));
+            // The sealed generations are kept, along with their stale records.
+            writer.uncompacted_size += writer.sealed_stale;
+            writer.sealed_gen = 0;
+        }
+        writer.sealed_stale = 0;
+        writer.compacting = false;
+        result
+    }
+
+    /// Copies the live records of the sealed generations into the compacted log.
+    ///
+    /// Returns each key with its old and new position. Expired keys are not copied
+    /// and have no new position.
+    fn copy(&self) -> Result<Vec<(Vec<u8>, CommandPosition, Option<CommandPosition>)>> {
+        let tmp_path = compacting_path(&self.path, self.compact_gen);
+        let mut compact_writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut moved = Vec::new();
+        let mut new_pos = 0;
+        let now = now_millis();
+        for entry in self.index.iter() {
+            let old_pos = *entry.value();
+            if old_pos.gen >= self.compact_gen {
+                continue;
+            }
+            if old_pos.is_expired(now) {
+                moved.push((entry.key().clone(), old_pos, None));
+                continue;
+            }
+            let len = self.reader.read_and(old_pos, |mut entry_reader| {
+                Ok(io::copy(&mut entry_reader, &mut compact_writer)?)
+            })?;
+            let new_pos_entry = CommandPosition {
+                gen: self.compact_gen,
+                position: new_pos,
+                length: len,
+                expires_at: old_pos.expires_at,
+            };
+            moved.push((entry.key().clone(), old_pos, Some(new_pos_entry)));
+            new_pos += len;
+        }
+        compact_writer.flush()?;
+        if self.durable {
+            compact_writer.get_ref().sync_all()?;
+        }
+        // The compacted log only appears under its real name once it is complete.
+        fs::rename(&tmp_path, log_path(&self.path, self.compact_gen))?;
+
+        // The hint file only speeds up the next open, so failing to write it is not fatal.
+        let hint: Vec<_> = moved
+            .iter()
+            .filter_map(|(key, _, new_pos)| new_pos.map(|new_pos| (key.clone(), new_pos)))
+            .collect();
+        let hint_path = hint_path(&self.path, self.compact_gen);
+        if let Err(e) = write_hint(&hint_path, &hint, self.durable) {
+            warn!("Error on writing hint file {}: {}", hint_path.display(), e);
+        }
+
+        if self.durable {
+            // The compacted log and its directory entry must be on disk before the
+            // generations it replaces are deleted.
+            sync_dir(&self.path)?;
+        }
+        Ok(moved)
+    }
+
+    /// Points the index at the compacted log and deletes the stale generations.
+    ///
+    /// Runs under the writer lock, so no write can race with the per-key switch.
+    fn switch(
+        &self,
+        writer: &mut KvStoreWriter,
+        moved: Vec<(Vec<u8>, CommandPosition, Option<CommandPosition>)>,
+    ) -> Result<()> {
+        for (key, old_pos, new_pos) in moved {
+            // Keys written or removed since the generation was sealed keep their
+            // newer entry, which makes the copy stale. It is only counted here, see
+            // `KvStoreWriter::displace`.
+            let unchanged = self
+                .index
+                .get(&key)
+                .map_or(false, |entry| *entry.value() == old_pos);
+            match new_pos {
+                Some(new_pos) if unchanged => {
+                    self.index.insert(key, new_pos);
+                }
+                Some(new_pos) => writer.uncompacted_size += new_pos.length,
+                None if unchanged => {
+                    self.index.remove(&key);
+                    writer.live_size -= old_pos.length;
+                }
+                None => {}
+            }
+        }
+
+        writer.generations += 1;
+
+        self.reader
+            .safe_point
+            .store(self.compact_gen, Ordering::SeqCst);
+        let stale_gens = sort_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < self.compact_gen);
+        for stale_gen in stale_gens {
+            fs::remove_file(log_path(&self.path, stale_gen))?;
+            remove_if_exists(&hint_path(&self.path, stale_gen))?;
+            writer.generations -= 1;
+        }
+        Ok(())
+    }
+}
+
+/// Runs compactions in a background thread, one at a time.
+///
+/// Joins the thread when the last `KvStore` handle is dropped, so the data directory
+/// is not touched after that.
+#[derive(Default)]
+struct Compactor {
+    handle: Mutex<Option<JoinHandle<()>>>,
+    progress: Arc<CompactionProgress>,
+}
+
+/// Shared with the compaction thread, so every caller of `compact` waiting on a
+/// compaction sees it finish.
+#[derive(Default)]
+struct CompactionProgress {
+    state: Mutex<ProgressState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    started: u64,
+    finished: u64,
+    /// Result of the latest finished compaction.
+    result: Option<Result<()>>,
+}
+
+impl Compactor {
+    /// Must be called under the writer lock, like `KvStoreWriter::seal`, so that a
+    /// running compaction is always counted as started.
+    fn spawn(&self, compaction: Compaction, writer: Arc<Mutex<KvStoreWriter>>) {
+        let mut handle = self.handle.lock().unwrap();
+        // At most one compaction runs at a time, so the previous one is finishing.
+        if let Some(previous) = handle.take() {
+            let _ = previous.join();
+        }
+        self.progress.state.lock().unwrap().started += 1;
+        let progress = Arc::clone(&self.progress);
+        *handle = Some(thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| compaction.run(&writer)))
+                .unwrap_or_else(|_| Err(KvsError::Other("Compaction thread panicked".to_owned())));
+            let mut state = progress.state.lock().unwrap();
+            state.finished += 1;
+            state.result = Some(result);
+            progress.finished.notify_all();
+        }));
+    }
+
+    /// Waits for the compactions started so far to finish and returns the result of
+    /// the latest one.
+    fn wait(&self) -> Result<()> {
+        let state = self.progress.state.lock().unwrap();
+        let started = state.started;
+        let state = self
+            .progress
+            .finished
+            .wait_while(state, |state| state.finished < started)
+            .unwrap();
+        match &state.result {
+            Some(Err(e)) => Err(copy_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Copies an error every waiter of a compaction is handed. `KvsError` is not `Clone`,
+/// because `io::Error` is not.
+fn copy_error(e: &KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) => io::Error::new(e.kind(), e.to_string()).into(),
+        KvsError::Corruption { gen, offset } => KvsError::Corruption {
+            gen: *gen,
+            offset: *offset,
+        },
+        KvsError::Serialization(msg) => KvsError::Serialization(msg.clone()),
+        e => KvsError::Other(e.to_string()),
+    }
+}
+
+/// Result of replaying one generation into the index.
+struct Replay {
+    uncompacted_size: u64,
+    /// Length of the prefix made of complete, valid records.
+    valid_len: u64,
+    /// Why replay stopped before the end of the file, if it did.
+    error: Option<io::Error>,
+}
+
+/// A batch read from the log whose commit marker has not shown up yet.
+struct ReplayBatch {
+    /// Offset of the begin marker.
+    start: u64,
+    len: u32,
+    /// Commands of the batch, applied once the commit marker is read.
+    commands: Vec<(Command, CommandPosition)>,
+}
+
+fn build_index(
+    gen: u64,
+    reader: &mut BufReader<File>,
+    index: &mut SkipMap<Vec<u8>, CommandPosition>,
+) -> Result<Replay> {
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut uncompacted_size = 0;
+    let mut batch: Option<ReplayBatch> = None;
+    let now = now_millis();
+
+    let error = loop {
+        let (command, length) = match read_record(reader) {
+            Ok(Some(record)) => record,
+            Ok(None) if batch.is_none() => break None,
+            Ok(None) => break Some(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Err(e)
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    || e.kind() == io::ErrorKind::InvalidData =>
+            {
+                break Some(e)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let cmd_pos = CommandPosition {
+            position: pos,
+            length,
+            gen,
+            expires_at: match &command {
+                Command::Set { expires_at, .. } => *expires_at,
+                _ => None,
+            },
+        };
+        match command {
+            Command::BatchBegin { len } => {
+                if batch.is_some() {
+                    break Some(invalid_batch("batch begins inside another batch"));
+                }
+                batch = Some(ReplayBatch {
+                    start: pos,
+                    len,
+                    commands: Vec::new(),
+                });
+                uncompacted_size += length;
+            }
+            Command::BatchCommit { len } => match batch.take() {
+                Some(ReplayBatch {
+                    len: begin_len,
+                    commands,
+                    ..
+                }) if begin_len == len && commands.len() == len as usize => {
+                    for (command, cmd_pos) in commands {
+                        uncompacted_size += replay_command(index, command, cmd_pos, now);
+                    }
+                    uncompacted_size += length;
+                }
+                unmatched => {
+                    batch = unmatched;
+                    break Some(invalid_batch("batch commit does not match its begin"));
+                }
+            },
+            command => match &mut batch {
+                Some(batch) => batch.commands.push((command, cmd_pos)),
+                None => uncompacted_size += replay_command(index, command, cmd_pos, now),
+            },
+        }
+        pos += length;
+    };
+    // A batch that was cut short is dropped as a whole, starting at its begin marker.
+    let valid_len = match (&error, &batch) {
+        (Some(_), Some(batch)) => batch.start,
+        _ => pos,
+    };
+    Ok(Replay {
+        uncompacted_size,
+        valid_len,
+        error,
+    })
+}
+
+/// Applies a command read from the log to `index` and returns the bytes it made stale.
+///
+/// A set that has expired by `now` removes the key, as it would have if it had been
+/// read before expiring.
+fn replay_command(
+    index: &SkipMap<Vec<u8>, CommandPosition>,
+    command: Command,
+    cmd_pos: CommandPosition,
+    now: u64,
+) -> u64 {
+    match command {
+        Command::Set { key, .. } if cmd_pos.is_expired(now) => {
+            let stale = index
+                .remove(&key)
+                .map_or(0, |old_cmd| old_cmd.value().length);
+            stale + cmd_pos.length
+        }
+        Command::Set { key, .. } => {
+            let stale = index.get(&key).map_or(0, |old_cmd| old_cmd.value().length);
+            index.insert(key, cmd_pos);
+            stale
+        }
+        Command::Remove { key } => match index.remove(&key) {
+            Some(_) => cmd_pos.length,
+            None => 0,
+        },
+        _ => unreachable!("batch markers are handled by build_index"),
+    }
+}
+
+fn invalid_batch(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Error for a record that cannot be read back at `cmd_pos`.
+fn corruption(cmd_pos: CommandPosition) -> KvsError {
+    KvsError::Corruption {
+        gen: cmd_pos.gen,
+        offset: cmd_pos.position,
+    }
+}
+
+/// Encodes a batch marker, whose payload is a fixed four bytes.
+fn encode_marker(marker: Command) -> Vec<u8> {
+    marker
+        .encode()
+        .expect("batch markers always fit in a record")
+}
+
+/// Cuts the log at `path` down to `len` bytes, returning how many bytes were dropped.
+fn truncate_log(path: &Path, len: u64, cause: &io::Error) -> Result<u64> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    let dropped = file.metadata()?.len() - len;
+    file.set_len(len)?;
+    file.sync_all()?;
+    warn!(
+        "Dropped {} bytes after offset {} of {}: {}",
+        dropped,
+        len,
+        path.display(),
+        cause
+    );
+    Ok(dropped)
+}
+
+/// Makes created, renamed and deleted files in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+fn compacting_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.compacting", gen))
+}
+
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn sort_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .filter_map(|result| result.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(ffi::OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Space usage of a `KvStore`, as seen by its `CompactionPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// Bytes taken by overwritten and removed records that compaction would reclaim.
+    pub stale_bytes: u64,
+    /// Bytes taken by the records holding the current values.
+    pub live_bytes: u64,
+    /// Number of log files, including the one being written.
+    pub generations: usize,
+    /// Whether a compaction is running.
+    pub compacting: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CommandPosition {
+    position: u64,
+    length: u64,
+    gen: u64,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+}
+
+impl CommandPosition {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+}
@@ -0,0 +1,129 @@
+use super::KvStore;
+use crate::{KvsError, Result};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// When writes to a `KvStore` are fsynced to disk.
+///
+/// Until a write is fsynced it only lives in the operating system's page cache and can
+/// be lost on power failure, even though it has already been acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Fsync after every write, before it is acknowledged.
+    Always,
+    /// Fsync after every N writes.
+    EveryN(u32),
+    /// Fsync pending writes in the background every given number of milliseconds.
+    Interval(u64),
+    /// Never fsync and leave write-back to the operating system.
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> SyncPolicy {
+        SyncPolicy::Never
+    }
+}
+
+/// Parses `always`, `never`, `every:N` or `interval:MS`.
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<SyncPolicy> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("always"), None) => Ok(SyncPolicy::Always),
+            (Some("never"), None) => Ok(SyncPolicy::Never),
+            (Some("every"), Some(n)) => match n.parse() {
+                // Syncing every 0 writes makes no sense.
+                Ok(0) | Err(_) => Err(KvsError::InvalidSyncPolicy(s.to_owned())),
+                Ok(n) => Ok(SyncPolicy::EveryN(n)),
+            },
+            (Some("interval"), Some(ms)) => match ms.parse() {
+                // The syncer would fsync in a busy loop.
+                Ok(0) | Err(_) => Err(KvsError::InvalidSyncPolicy(s.to_owned())),
+                Ok(ms) => Ok(SyncPolicy::Interval(ms)),
+            },
+            _ => Err(KvsError::InvalidSyncPolicy(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryN(n) => write!(f, "every:{}", n),
+            SyncPolicy::Interval(ms) => write!(f, "interval:{}", ms),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// When a `KvStore` compacts its log in the background.
+///
+/// Compaction rewrites the live records into a new generation and deletes the old
+/// ones, reclaiming the space taken by overwritten and removed records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compact once stale records take up more than this many bytes.
+    StaleBytes(u64),
+    /// Compact once stale bytes exceed this multiple of the live bytes.
+    StaleRatio(f64),
+    /// Compact once there are more than this many log files. Values below 2 act as 2,
+    /// since a compaction leaves two generations behind.
+    MaxGenerations(usize),
+    /// Only compact when `KvStore::compact` is called.
+    Manual,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> CompactionPolicy {
+        CompactionPolicy::StaleBytes(1024 * 1024)
+    }
+}
+
+/// Options used to open a `KvStore`.
+///
+/// Example:
+///
+/// ```rust
+/// use kvs::{KvStore, KvStoreOptions, Result, SyncPolicy};
+/// use std::env::current_dir;
+/// fn try_main() -> Result<()> {
+/// let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+/// let store = KvStore::open_with_options(current_dir()?, options)?;
+/// Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) compaction_policy: CompactionPolicy,
+}
+
+impl KvStoreOptions {
+    /// Creates options with every setting at its default.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets when writes are fsynced. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets when the log is compacted. Defaults to `CompactionPolicy::StaleBytes`
+    /// with a 1 MiB threshold.
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> KvStoreOptions {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// Opens a `KvStore` at `path` with these options.
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
+}
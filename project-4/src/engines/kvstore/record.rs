@@ -0,0 +1,303 @@
+//! On-disk record format of the `KvStore` log.
+//!
+//! Every record is a fixed-size header followed by its payload:
+//!
+//! ```text
+//! +-----------+-------------+----------+------------+-----------+-------------------+
+//! | magic (2) | version (1) | type (1) | length (4) | crc32 (4) | payload (length)  |
+//! +-----------+-------------+----------+------------+-----------+-------------------+
+//! ```
+//!
+//! Integers are little-endian. The CRC covers the type byte and the payload, so a
+//! torn write or a flipped bit is reported as an error instead of being decoded.
+//!
+//! A set with a time to live is stored as its own record type, whose payload starts
+//! with the expiry time in milliseconds since the Unix epoch.
+//!
+//! The commands of a write batch are written between a `BatchBegin` and a
+//! `BatchCommit` marker, which both carry the number of commands in the batch.
+//!
+//! Logs written before this format existed are bare JSON objects appended back to
+//! back. They are detected by their first byte and rewritten by `upgrade_legacy_log`.
+
+use crate::{KvsError, Result};
+use crc32fast::Hasher;
+use log::{error, warn};
+use serde::Deserialize;
+use serde_json::Deserializer;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 2] = *b"KV";
+const VERSION: u8 = 1;
+/// Length in bytes of the header preceding every payload.
+pub(super) const HEADER_LEN: u64 = 12;
+
+const TYPE_SET: u8 = 1;
+const TYPE_REMOVE: u8 = 2;
+const TYPE_BATCH_BEGIN: u8 = 3;
+const TYPE_BATCH_COMMIT: u8 = 4;
+const TYPE_SET_EXPIRING: u8 = 5;
+
+/// Struct representing a command
+#[derive(Debug)]
+pub(super) enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the Unix epoch.
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// Starts a batch of `len` commands.
+    BatchBegin {
+        len: u32,
+    },
+    /// Ends a batch of `len` commands, which only apply once this marker is read.
+    BatchCommit {
+        len: u32,
+    },
+}
+
+impl Command {
+    /// Encodes the command as a complete framed record.
+    ///
+    /// Fails if the key or the payload is too long for its length field.
+    pub(super) fn encode(&self) -> Result<Vec<u8>> {
+        let (record_type, payload) = match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => {
+                let mut payload = Vec::with_capacity(12 + key.len() + value.len());
+                if let Some(expires_at) = expires_at {
+                    payload.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                payload.extend_from_slice(&encode_len(key.len(), "Key")?);
+                payload.extend_from_slice(key);
+                payload.extend_from_slice(value);
+                match expires_at {
+                    Some(_) => (TYPE_SET_EXPIRING, payload),
+                    None => (TYPE_SET, payload),
+                }
+            }
+            Command::Remove { key } => (TYPE_REMOVE, key.clone()),
+            Command::BatchBegin { len } => (TYPE_BATCH_BEGIN, len.to_le_bytes().to_vec()),
+            Command::BatchCommit { len } => (TYPE_BATCH_COMMIT, len.to_le_bytes().to_vec()),
+        };
+
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&MAGIC);
+        record.push(VERSION);
+        record.push(record_type);
+        record.extend_from_slice(&encode_len(payload.len(), "Record")?);
+        record.extend_from_slice(&checksum(record_type, &payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    fn decode(record_type: u8, payload: Vec<u8>) -> io::Result<Command> {
+        match record_type {
+            TYPE_SET => decode_set(&payload, None),
+            TYPE_SET_EXPIRING => {
+                if payload.len() < 8 {
+                    return Err(invalid_data("set record too short"));
+                }
+                let mut expires_at = [0; 8];
+                expires_at.copy_from_slice(&payload[..8]);
+                decode_set(&payload[8..], Some(u64::from_le_bytes(expires_at)))
+            }
+            TYPE_REMOVE => Ok(Command::Remove { key: payload }),
+            TYPE_BATCH_BEGIN => Ok(Command::BatchBegin {
+                len: batch_len(&payload)?,
+            }),
+            TYPE_BATCH_COMMIT => Ok(Command::BatchCommit {
+                len: batch_len(&payload)?,
+            }),
+            _ => Err(invalid_data("unknown record type")),
+        }
+    }
+}
+
+fn decode_set(payload: &[u8], expires_at: Option<u64>) -> io::Result<Command> {
+    if payload.len() < 4 {
+        return Err(invalid_data("set record too short"));
+    }
+    let mut key_len = [0; 4];
+    key_len.copy_from_slice(&payload[..4]);
+    let key_end = 4 + u32::from_le_bytes(key_len) as usize;
+    if key_end > payload.len() {
+        return Err(invalid_data("key length out of bounds"));
+    }
+    Ok(Command::Set {
+        key: payload[4..key_end].to_vec(),
+        value: payload[key_end..].to_vec(),
+        expires_at,
+    })
+}
+
+/// Reads the next record from `reader`.
+///
+/// Returns `Ok(None)` at a clean end of file. A record cut short by the end of file
+/// is reported as `UnexpectedEof`; a record failing the magic, version or CRC check
+/// is reported as `InvalidData`.
+pub(super) fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<(Command, u64)>> {
+    let mut header = [0; HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    } else if read < header.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if header[..2] != MAGIC {
+        return Err(invalid_data("bad magic"));
+    }
+    if header[2] != VERSION {
+        return Err(invalid_data("unsupported record version"));
+    }
+    let record_type = header[3];
+    let mut word = [0; 4];
+    word.copy_from_slice(&header[4..8]);
+    let length = u32::from_le_bytes(word);
+    word.copy_from_slice(&header[8..12]);
+    let crc = u32::from_le_bytes(word);
+
+    let mut payload = Vec::new();
+    reader.take(u64::from(length)).read_to_end(&mut payload)?;
+    if payload.len() < length as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if checksum(record_type, &payload) != crc {
+        return Err(invalid_data("checksum mismatch"));
+    }
+    let command = Command::decode(record_type, payload)?;
+    Ok(Some((command, HEADER_LEN + u64::from(length))))
+}
+
+/// Returns true if the log at `path` predates the binary record format.
+pub(super) fn is_legacy_log(path: &Path) -> Result<bool> {
+    let mut first = [0; 1];
+    let read = read_full(&mut File::open(path)?, &mut first)?;
+    Ok(read == 1 && first[0] == b'{')
+}
+
+/// Rewrites legacy JSON log `gen` in place using the binary record format.
+///
+/// The new log is written next to the old one and renamed over it, so a crash
+/// during the upgrade leaves the legacy log untouched. An object that cannot be
+/// parsed is reported as `KvsError::Corruption`, unless `recover` is set: the log
+/// is then cut after the last object that parsed. Returns the number of bytes
+/// dropped.
+pub(super) fn upgrade_legacy_log(path: &Path, gen: u64, recover: bool) -> Result<u64> {
+    let tmp_path = path.with_extension("log.upgrade");
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?,
+    );
+    let mut commands = Deserializer::from_reader(BufReader::new(file)).into_iter::<LegacyCommand>();
+    let mut dropped = 0;
+    loop {
+        let valid_len = commands.byte_offset() as u64;
+        let command = match commands.next() {
+            Some(Ok(command)) => command,
+            None => break,
+            Some(Err(e)) if e.is_io() => return Err(io::Error::from(e).into()),
+            Some(Err(e)) if recover => {
+                dropped = len - valid_len;
+                warn!(
+                    "Dropped {} bytes after offset {} of {}: {}",
+                    dropped,
+                    valid_len,
+                    path.display(),
+                    e
+                );
+                break;
+            }
+            Some(Err(e)) => {
+                error!("Corrupt record in {}: {}", path.display(), e);
+                return Err(KvsError::Corruption {
+                    gen,
+                    offset: valid_len,
+                });
+            }
+        };
+        let command = match command {
+            LegacyCommand::Set { key, value } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            LegacyCommand::Remove { key } => Command::Remove {
+                key: key.into_bytes(),
+            },
+        };
+        writer.write_all(&command.encode()?)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(dropped)
+}
+
+/// Command as serialized by the JSON log format.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Encodes a length field, which holds at most `u32::MAX`.
+fn encode_len(len: usize, what: &str) -> Result<[u8; 4]> {
+    match u32::try_from(len) {
+        Ok(len) => Ok(len.to_le_bytes()),
+        Err(_) => Err(KvsError::Other(format!(
+            "{} of {} bytes is too large for the log",
+            what, len
+        ))),
+    }
+}
+
+fn checksum(record_type: u8, payload: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Like `read_exact`, but returns the number of bytes read when the reader hits the
+/// end of file early instead of failing.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn batch_len(payload: &[u8]) -> io::Result<u32> {
+    if payload.len() != 4 {
+        return Err(invalid_data("bad batch marker"));
+    }
+    Ok(u32::from_le_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -1,26 +1,179 @@
 use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub use kvstore::KvStore;
+pub use batch::{BatchOp, WriteBatch};
+pub use kvstore::{CompactionPolicy, KvStore, KvStoreOptions, KvStoreStats, SyncPolicy};
 pub use sled_engine::SledEngine;
 
+mod batch;
 mod kvstore;
 mod sled_engine;
+
+/// Returned by `KvsEngine::compare_and_swap` when the key did not hold the expected
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareAndSwapError {
+    /// The value the key held instead, `None` if it did not exist.
+    pub current: Option<Vec<u8>>,
+}
+
+/// Ordered key-value pairs returned by a scan.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>>>;
+
+/// Ordered key-value pairs returned by a byte-oriented scan.
+pub type ScanBytesIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
 /// Trait for a key value storage engine.
+///
+/// Keys and values are arbitrary bytes. The methods taking and returning strings are
+/// wrappers around the byte-oriented ones, and fail on values that are not UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()>;
 
-    /// Gets the string value of a given string key.
+    /// Sets the value of a key, which expires after `ttl`.
+    ///
+    /// Once expired, the key reads as if it did not exist. Setting the key again
+    /// replaces the expiry along with the value.
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()>;
+
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Applies every write of `batch` as a unit.
+    ///
+    /// Either all of the writes are persisted or none are, so a crash never leaves
+    /// only part of the batch behind. Removing a missing key inside a batch is not
+    /// an error.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets `key` to `new` only if its current value is `expected`.
+    ///
+    /// `None` stands for a missing key, so `expected: None` only writes a key that
+    /// does not exist yet and `new: None` removes the key. The check and the write
+    /// happen atomically. Returns `Ok(Err(_))` with the current value if it did not
+    /// match `expected`.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>>;
+
+    /// Returns the key-value pairs whose keys fall in `range`, in key order.
+    ///
+    /// At most `limit` pairs are returned when a limit is given.
+    fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter>
+    where
+        R: RangeBounds<Vec<u8>>;
+
+    /// Returns the key-value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// At most `limit` pairs are returned when a limit is given.
+    fn scan_prefix_bytes(&self, prefix: &[u8], limit: Option<usize>) -> Result<ScanBytesIter>;
+
+    /// Writes every buffered write through to disk and fsyncs it, whatever the sync
+    /// policy of the engine.
+    fn flush(&self) -> Result<()>;
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Sets the value of a string key to a string that expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(from_utf8).transpose()
+    }
+
+    /// Removes a given string key.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Sets a string key to `new` only if its current value is `expected`.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )
+    }
+
+    /// Returns the string pairs whose keys fall in `range`, in key order.
+    fn scan<R>(&self, range: R, limit: Option<usize>) -> Result<ScanIter>
+    where
+        R: RangeBounds<String>,
+    {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        Ok(Box::new(self.scan_bytes(range, limit)?.map(from_utf8_pair)))
+    }
+
+    /// Returns the string pairs whose keys start with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: String, limit: Option<usize>) -> Result<ScanIter> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.as_bytes(), limit)?
+                .map(from_utf8_pair),
+        ))
+    }
+}
+
+fn from_utf8(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn from_utf8_pair(pair: Result<(Vec<u8>, Vec<u8>)>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((from_utf8(key)?, from_utf8(value)?))
+}
+
+/// Strings order the same as their UTF-8 bytes, so string ranges map directly.
+fn bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit expiry
+/// times are stored in.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns the expiry time of a key written now with the given time to live.
+pub(crate) fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
 }
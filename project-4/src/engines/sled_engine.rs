@@ -1,43 +1,213 @@
-use crate::engines::KvsEngine;
-use crate::Result;
-use failure::format_err;
-use sled::Db;
+use crate::engines::{
+    expiry_after, now_millis, BatchOp, CompareAndSwapError, KvsEngine, ScanBytesIter, WriteBatch,
+};
+use crate::{KvsError, Result};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use sled::{Batch, Db, IVec, Iter, Transactional, Tree};
+use std::ops::RangeBounds;
 use std::path::Path;
+use std::time::Duration;
 ///SledEngine
 #[derive(Clone)]
-pub struct SledEngine(Db);
+pub struct SledEngine {
+    db: Db,
+    /// Expiry time of every key set with a time to live, in milliseconds since the
+    /// Unix epoch. It is updated in the same transaction as the value.
+    expiries: Tree,
+}
 
 impl SledEngine {
     ///open SledEngine
     pub fn open(path: &Path) -> Result<SledEngine> {
         let db = sled::open(path)?;
-        Ok(SledEngine(db))
+        let expiries = db.open_tree("expiries")?;
+        Ok(SledEngine { db, expiries })
+    }
+
+    /// Sets `key` and its expiry, or clears the expiry if `expires_at` is `None`.
+    fn set_expiring(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        (&*self.db, &self.expiries)
+            .transaction(|(db, expiries)| {
+                db.insert(key, value)?;
+                set_expiry(expiries, key, expires_at)?;
+                Ok(())
+            })
+            .map_err(storage_error::<()>)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan_iter(&self, iter: Iter, limit: Option<usize>) -> ScanBytesIter {
+        let expiries = self.expiries.clone();
+        let now = now_millis();
+        let iter = iter
+            .map(move |entry| -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+                let (key, value): (IVec, IVec) = entry?;
+                if is_expired(expiries.get(&key)?, now) {
+                    return Ok(None);
+                }
+                Ok(Some((key.to_vec(), value.to_vec())))
+            })
+            .filter_map(Result::transpose);
+        match limit {
+            Some(limit) => Box::new(iter.take(limit)),
+            None => Box::new(iter),
+        }
     }
 }
 
 impl KvsEngine for SledEngine {
-    ///Set a key-value pair of String.
+    ///Set a key-value pair.
     ///
     /// If the key already exists, value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.0.insert(key, value.as_bytes())?;
-        self.0.flush()?;
-        Ok(())
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set_expiring(key, value, None)
     }
-    ///Get the String value of a String key.
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.set_expiring(key, value, Some(expiry_after(ttl)))
+    }
+
+    ///Get the value of a key.
     ///
     /// Return NONE if the key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .0
-            .get(key)?
-            .map(|v| String::from_utf8_lossy(&v).to_string()))
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = match self.db.get(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if is_expired(self.expiries.get(key)?, now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(value.to_vec()))
     }
 
     ///Remove the given key.
-    fn remove(&self, key: String) -> Result<()> {
-        self.0.remove(key)?.ok_or(format_err!("key not found"))?;
-        self.0.flush()?;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let now = now_millis();
+        (&*self.db, &self.expiries)
+            .transaction(|(db, expiries)| {
+                let removed = db.remove(key)?;
+                let expires_at = expiries.remove(key)?;
+                if removed.is_none() || is_expired(expires_at, now) {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(()) => KvsError::KeyNotFound,
+                e => storage_error(e),
+            })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = Batch::default();
+        let mut expiry_batch = Batch::default();
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => {
+                    expiry_batch.remove(key.as_slice());
+                    sled_batch.insert(key, value);
+                }
+                BatchOp::Remove { key } => {
+                    expiry_batch.remove(key.as_slice());
+                    sled_batch.remove(key);
+                }
+            }
+        }
+        (&*self.db, &self.expiries)
+            .transaction(|(db, expiries)| {
+                db.apply_batch(&sled_batch)?;
+                expiries.apply_batch(&expiry_batch)?;
+                Ok(())
+            })
+            .map_err(storage_error::<()>)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        // `Tree::compare_and_swap` would see expired values, so the comparison runs
+        // in a transaction that also reads the expiry.
+        let now = now_millis();
+        let swapped = (&*self.db, &self.expiries).transaction(|(db, expiries)| {
+            let current = match db.get(key)? {
+                Some(_) if is_expired(expiries.get(key)?, now) => None,
+                current => current,
+            };
+            if current.as_ref().map(|value| &value[..]) != expected {
+                return Err(ConflictableTransactionError::Abort(
+                    current.map(|value| value.to_vec()),
+                ));
+            }
+            match new {
+                Some(value) => db.insert(key, value)?,
+                None => db.remove(key)?,
+            };
+            set_expiry(expiries, key, None)?;
+            Ok(())
+        });
+        match swapped {
+            Ok(()) => {
+                self.db.flush()?;
+                Ok(Ok(()))
+            }
+            Err(TransactionError::Abort(current)) => Ok(Err(CompareAndSwapError { current })),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        Ok(self.scan_iter(self.db.range(range), limit))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8], limit: Option<usize>) -> Result<ScanBytesIter> {
+        Ok(self.scan_iter(self.db.scan_prefix(prefix), limit))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
         Ok(())
     }
 }
+
+fn set_expiry<E>(
+    expiries: &TransactionalTree,
+    key: &[u8],
+    expires_at: Option<u64>,
+) -> std::result::Result<(), ConflictableTransactionError<E>> {
+    match expires_at {
+        Some(expires_at) => expiries.insert(key, &expires_at.to_le_bytes()[..])?,
+        None => expiries.remove(key)?,
+    };
+    Ok(())
+}
+
+/// Returns true if a key with the given stored expiry has expired by `now`.
+fn is_expired(expires_at: Option<IVec>, now: u64) -> bool {
+    match expires_at {
+        Some(expires_at) if expires_at.len() == 8 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&expires_at);
+            u64::from_le_bytes(bytes) <= now
+        }
+        _ => false,
+    }
+}
+
+fn storage_error<E>(e: TransactionError<E>) -> KvsError {
+    match e {
+        TransactionError::Storage(e) => e.into(),
+        TransactionError::Abort(_) => KvsError::Other("Transaction aborted".to_owned()),
+    }
+}
@@ -1,4 +1,91 @@
-use failure::Error;
+use crate::ErrorCode;
+use failure::Fail;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// Error type for kvs.
+#[derive(Fail, Debug)]
+pub enum KvsError {
+    /// Removing a key that does not exist.
+    #[fail(display = "Key not found")]
+    KeyNotFound,
+    /// IO error.
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    /// A message or record could not be encoded or decoded.
+    #[fail(display = "Serialization error: {}", _0)]
+    Serialization(String),
+    /// A string API met a key or value that is not valid UTF-8.
+    #[fail(display = "{}", _0)]
+    Utf8(#[cause] FromUtf8Error),
+    /// Error from the sled engine.
+    #[fail(display = "sled error: {}", _0)]
+    Sled(#[cause] sled::Error),
+    /// A log record failed its integrity checks.
+    #[fail(
+        display = "Corrupt record in generation {} at offset {}. Run `kvs-repair` in the data directory to salvage the log",
+        gen, offset
+    )]
+    Corruption {
+        /// Generation of the log file holding the record.
+        gen: u64,
+        /// Offset of the record in the log file.
+        offset: u64,
+    },
+    /// The data directory was created by another engine.
+    #[fail(display = "Wrong engine")]
+    WrongEngine,
+    /// The peer sent a message that breaks the protocol.
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// The server failed to handle a request.
+    #[fail(display = "Server error ({}): {}", code, message)]
+    ServerError {
+        /// Kind of failure reported by the server.
+        code: ErrorCode,
+        /// Description of the failure.
+        message: String,
+    },
+    /// A sync policy that cannot be parsed.
+    #[fail(
+        display = "Invalid sync policy {:?}, expected always, never, every:N or interval:MS",
+        _0
+    )]
+    InvalidSyncPolicy(String),
+    /// Any other error, with a message.
+    #[fail(display = "{}", _0)]
+    Other(String),
+}
+
+impl From<io::Error> for KvsError {
+    fn from(err: io::Error) -> KvsError {
+        KvsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::Serialization(err.to_string())
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Serialization(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::Sled(err)
+    }
+}
 
 ///Result type for kvs
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, KvsError>;
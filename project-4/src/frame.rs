@@ -0,0 +1,105 @@
+//! Framing of the messages exchanged between `kvs-client` and `kvs-server`.
+//!
+//! Every message is a bincode payload preceded by its length:
+//!
+//! ```text
+//! | length (4) | payload (length) |
+//! ```
+//!
+//! The length is little-endian. Bincode writes byte strings as a length and the raw
+//! bytes, so keys and values travel without any escaping.
+
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest payload accepted, so a corrupt length cannot trigger a huge allocation.
+const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
+
+/// Writes `message` as one frame.
+///
+/// `writer` is not flushed, so several frames can be buffered and sent together.
+pub(crate) fn write_frame<W: Write, T: Serialize>(mut writer: W, message: &T) -> Result<()> {
+    let payload = bincode::serialize(message)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(KvsError::Protocol(format!(
+            "Frame of {} bytes is too large",
+            payload.len()
+        )));
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads one frame from `reader` and decodes it.
+pub(crate) fn read_frame<R: Read, T: DeserializeOwned>(reader: R) -> Result<T> {
+    read_next_frame(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+/// Like `read_frame`, but returns `Ok(None)` if `reader` ends before the next frame.
+pub(crate) fn read_next_frame<R: Read, T: DeserializeOwned>(mut reader: R) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut payload = vec![0; payload_len(len)?];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(bincode::deserialize(&payload)?))
+}
+
+/// Async version of `write_frame`.
+#[cfg(feature = "async")]
+pub(crate) async fn write_frame_async<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut frame = Vec::new();
+    write_frame(&mut frame, message)?;
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Async version of `read_next_frame`.
+#[cfg(feature = "async")]
+pub(crate) async fn read_next_frame_async<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]).await? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            n => read += n,
+        }
+    }
+    let mut payload = vec![0; payload_len(len)?];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(bincode::deserialize(&payload)?))
+}
+
+/// Decodes the length prefix of a frame.
+fn payload_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Protocol(format!(
+            "Frame of {} bytes is too large",
+            len
+        )));
+    }
+    Ok(len as usize)
+}
@@ -1,15 +1,24 @@
 #![deny(missing_docs)]
-#![feature(seek_convenience)]
 //! A key-value store.
 
-pub use engines::{KvStore, KvsEngine, SledEngine};
-pub use error::Result;
+#[cfg(feature = "async")]
+pub use client::AsyncKvsClient;
+pub use client::{KvsClient, KvsClientOptions, KvsClientPool, KvsClientPoolOptions, PooledClient};
+pub use engines::{
+    BatchOp, CompactionPolicy, CompareAndSwapError, KvStore, KvStoreOptions, KvStoreStats,
+    KvsEngine, ScanBytesIter, ScanIter, SledEngine, SyncPolicy, WriteBatch,
+};
+pub use error::{KvsError, Result};
 pub use request::KvsRequest;
-pub use response::KvsResponse;
-pub use server::KvsServer;
+pub use response::{ErrorCode, KvsResponse};
+#[cfg(feature = "async")]
+pub use server::AsyncKvsServer;
+pub use server::{KvsServer, ShutdownHandle};
 
+mod client;
 mod engines;
 mod error;
+mod frame;
 mod request;
 mod response;
 mod server;
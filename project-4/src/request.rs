@@ -1,4 +1,7 @@
+use crate::frame::{read_frame, write_frame};
+use crate::{Result, WriteBatch};
 use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
 
 ///KvsRequest
 #[derive(Serialize, Deserialize, Debug)]
@@ -6,18 +9,74 @@ pub enum KvsRequest {
     ///Get command
     Get {
         ///key
-        key: String,
+        key: Vec<u8>,
     },
     ///Set command
     Set {
         ///key
-        key: String,
+        key: Vec<u8>,
         ///value
-        value: String,
+        value: Vec<u8>,
+        ///seconds after which the key expires, never if missing
+        ttl: Option<u64>,
     },
     /// Remove command
     Remove {
         ///key
-        key: String,
+        key: Vec<u8>,
     },
+    /// Scan command over the keys in `[start, end)`
+    Scan {
+        ///first key, unbounded if missing
+        start: Option<Vec<u8>>,
+        ///key after the last one, unbounded if missing
+        end: Option<Vec<u8>>,
+        ///maximum number of pairs
+        limit: Option<usize>,
+    },
+    /// Scan command over the keys starting with `prefix`
+    ScanPrefix {
+        ///prefix
+        prefix: Vec<u8>,
+        ///maximum number of pairs
+        limit: Option<usize>,
+    },
+    /// Batch command, applied as a unit
+    Batch {
+        ///writes of the batch
+        batch: WriteBatch,
+    },
+    /// Compare-and-swap command
+    Cas {
+        ///key
+        key: Vec<u8>,
+        ///value the key must hold, `None` if it must not exist
+        expected: Option<Vec<u8>>,
+        ///value to write, `None` to remove the key
+        new: Option<Vec<u8>>,
+    },
+}
+
+impl KvsRequest {
+    /// Writes the request to `writer` as one frame.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<()> {
+        write_frame(writer, self)
+    }
+
+    /// Reads one request frame from `reader`.
+    pub fn read_from<R: Read>(reader: R) -> Result<KvsRequest> {
+        read_frame(reader)
+    }
+
+    /// Returns true if applying the request twice has the same effect as applying it
+    /// once, so it can be resent when the outcome of the first attempt is unknown.
+    pub(crate) fn is_idempotent(&self) -> bool {
+        match self {
+            KvsRequest::Get { .. }
+            | KvsRequest::Set { .. }
+            | KvsRequest::Scan { .. }
+            | KvsRequest::ScanPrefix { .. } => true,
+            KvsRequest::Remove { .. } | KvsRequest::Batch { .. } | KvsRequest::Cas { .. } => false,
+        }
+    }
 }
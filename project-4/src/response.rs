@@ -1,10 +1,100 @@
+use crate::frame::{read_frame, write_frame};
+use crate::{KvsError, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
 
 ///KvsResponse
 #[derive(Serialize, Deserialize, Debug)]
 pub enum KvsResponse {
-    ///Ok response
-    Ok(Option<String>),
-    ///Err response
-    Err(String),
+    ///Value of the key of a `Get`
+    Value(Vec<u8>),
+    ///The key of a `Get` or `Remove` does not exist
+    NotFound,
+    ///A `Set` was applied
+    Set,
+    ///A `Remove` was applied
+    Removed,
+    ///Key-value pairs of a scan, in key order
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    ///A `Batch` was applied
+    BatchApplied,
+    ///Outcome of a compare-and-swap
+    Cas {
+        ///whether the swap was applied
+        applied: bool,
+        ///value the key held instead of the expected one when not applied
+        current: Option<Vec<u8>>,
+    },
+    ///The request failed
+    Err {
+        ///kind of failure
+        code: ErrorCode,
+        ///description of the failure
+        message: String,
+    },
+}
+
+/// Kind of failure reported in `KvsResponse::Err`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The storage failed to read or write.
+    Io,
+    /// The storage holds a corrupt record.
+    Corruption,
+    /// A value could not be encoded or decoded.
+    Serialization,
+    /// The request broke the protocol.
+    Protocol,
+    /// Any other failure of the server.
+    Internal,
+    /// The server turned the connection away because all of its threads and its
+    /// queue were taken. The request was not run and can be retried later.
+    Busy,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::Io => write!(f, "io"),
+            ErrorCode::Corruption => write!(f, "corruption"),
+            ErrorCode::Serialization => write!(f, "serialization"),
+            ErrorCode::Protocol => write!(f, "protocol"),
+            ErrorCode::Internal => write!(f, "internal"),
+            ErrorCode::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl KvsResponse {
+    /// Writes the response to `writer` as one frame.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<()> {
+        write_frame(writer, self)
+    }
+
+    /// Reads one response frame from `reader`.
+    pub fn read_from<R: Read>(reader: R) -> Result<KvsResponse> {
+        read_frame(reader)
+    }
+}
+
+/// Reports an engine error to the client, keeping `KeyNotFound` apart from failures.
+impl From<KvsError> for KvsResponse {
+    fn from(err: KvsError) -> KvsResponse {
+        let code = match err {
+            KvsError::KeyNotFound => return KvsResponse::NotFound,
+            KvsError::Io(_) | KvsError::Sled(_) => ErrorCode::Io,
+            KvsError::Corruption { .. } => ErrorCode::Corruption,
+            KvsError::Serialization(_) | KvsError::Utf8(_) => ErrorCode::Serialization,
+            KvsError::Protocol(_) => ErrorCode::Protocol,
+            KvsError::ServerError { code, .. } => code,
+            KvsError::WrongEngine | KvsError::InvalidSyncPolicy(_) | KvsError::Other(_) => {
+                ErrorCode::Internal
+            }
+        };
+        KvsResponse::Err {
+            code,
+            message: err.to_string(),
+        }
+    }
 }
@@ -0,0 +1,240 @@
+use super::{
+    handle, ShutdownHandle, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_REQUESTS, DEFAULT_SHUTDOWN_TIMEOUT,
+};
+use crate::frame::{read_next_frame_async, write_frame_async};
+use crate::{KvsEngine, KvsError, KvsResponse, Result};
+use log::{debug, error, info, warn};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::{task, time};
+
+/// How often shutting down checks whether the open connections have finished.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A `KvsServer` running on the tokio runtime.
+///
+/// Connections are tasks rather than pool threads, so an idle client costs no
+/// thread. Engine calls block, so each request runs on tokio's blocking threads.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// use kvs::{AsyncKvsServer, KvStore, Result};
+/// fn try_main() -> Result<()> {
+/// let server = AsyncKvsServer::new(KvStore::open(std::env::current_dir()?)?);
+/// let mut runtime = tokio::runtime::Runtime::new()?;
+/// runtime.block_on(server.run("127.0.0.1:4000".parse().unwrap()))
+/// }
+/// ```
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: E,
+    idle_timeout: Duration,
+    max_requests: u64,
+    shutdown_timeout: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    /// Create an `AsyncKvsServer` with a given storage engine.
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            engine,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Sets how long a connection may wait for its next request before it is closed.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how many requests are answered on a connection before it is closed.
+    pub fn max_requests(mut self, max_requests: u64) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Sets how long shutting down waits for open connections to finish the requests
+    /// they have sent.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Returns a handle that stops `run` from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accepts connections and serves each of them on its own task.
+    ///
+    /// It must be run within a tokio runtime with the threaded scheduler. It returns
+    /// once shut down through a `ShutdownHandle`, after the open connections are
+    /// drained and the engine is flushed. Connections still busy after the shutdown
+    /// timeout are closed when the runtime is dropped.
+    pub async fn run(&self, addr: SocketAddr) -> Result<()> {
+        let mut listener = TcpListener::bind(addr).await?;
+        let open = Arc::new(AtomicUsize::new(0));
+        loop {
+            let accepted = match self
+                .shutdown
+                .until_requested(Box::pin(listener.accept()))
+                .await
+            {
+                Some(accepted) => accepted,
+                None => break,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Error on accepting client: {}", e);
+                    continue;
+                }
+            };
+            let connection = OpenConnection::new(&open);
+            let engine = self.engine.clone();
+            let idle_timeout = self.idle_timeout;
+            let max_requests = self.max_requests;
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                let result = serve(engine, stream, idle_timeout, max_requests, &shutdown).await;
+                if let Err(e) = result {
+                    error!("Error on serving client: {}", e);
+                }
+                drop(connection);
+            });
+        }
+        drop(listener);
+
+        info!("Shutting down");
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while open.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            time::delay_for(DRAIN_INTERVAL).await;
+        }
+        let busy = open.load(Ordering::SeqCst);
+        if busy > 0 {
+            warn!("Leaving {} connections with requests in progress", busy);
+        }
+        let engine = self.engine.clone();
+        task::spawn_blocking(move || engine.flush())
+            .await
+            .map_err(|e| KvsError::Other(format!("Flush failed: {}", e)))?
+    }
+}
+
+/// Counts a connection as open until it is dropped, which also happens when its task
+/// is cancelled.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    fn new(open: &Arc<AtomicUsize>) -> OpenConnection {
+        open.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(Arc::clone(open))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Async version of `super::serve`.
+///
+/// Once a shutdown is requested, the requests already received are still answered,
+/// but the connection is closed instead of waiting for more.
+async fn serve<E: KvsEngine>(
+    engine: E,
+    mut stream: TcpStream,
+    idle_timeout: Duration,
+    max_requests: u64,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    let (reader, writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    for _ in 0..max_requests {
+        if !frame_started(&mut reader, idle_timeout, shutdown).await? {
+            break;
+        }
+        // Once part of a frame is read, the read is not given up on shutdown, which
+        // would lose the request.
+        let request = match time::timeout(idle_timeout, read_next_frame_async(&mut reader)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                debug!("Closing idle connection");
+                break;
+            }
+        };
+        let engine = engine.clone();
+        let response = match task::spawn_blocking(move || handle(&engine, request)).await {
+            Ok(response) => response,
+            Err(e) => KvsResponse::from(KvsError::Other(format!("Request failed: {}", e))),
+        };
+        write_frame_async(&mut writer, &response).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Waits until the first bytes of the next frame are buffered in `reader`.
+///
+/// Returns false if the connection should be closed instead: the client closed it,
+/// it stayed idle for `idle_timeout`, or a shutdown is requested while no request
+/// has arrived. Filling the buffer consumes nothing, so it can be given up safely.
+async fn frame_started<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    idle_timeout: Duration,
+    shutdown: &ShutdownHandle,
+) -> Result<bool> {
+    if !shutdown.is_requested() {
+        let fill = time::timeout(idle_timeout, FillBuf(reader));
+        match shutdown.until_requested(Box::pin(fill)).await {
+            Some(Ok(buffered)) => return Ok(buffered? > 0),
+            Some(Err(_)) => {
+                debug!("Closing idle connection");
+                return Ok(false);
+            }
+            None => {}
+        }
+    }
+    // Only the requests that have already arrived are answered.
+    match time::timeout(Duration::from_secs(0), FillBuf(reader)).await {
+        Ok(buffered) => Ok(buffered? > 0),
+        Err(_) => {
+            debug!("Closing connection on shutdown");
+            Ok(false)
+        }
+    }
+}
+
+/// Fills the buffer of a reader, returning how many bytes it holds, zero at the end
+/// of the stream.
+struct FillBuf<'a, R>(&'a mut R);
+
+impl<R: AsyncBufRead + Unpin> Future for FillBuf<'_, R> {
+    type Output = io::Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0)
+            .poll_fill_buf(cx)
+            .map_ok(|buf| buf.len())
+    }
+}
@@ -0,0 +1,255 @@
+use self::shutdown::Connections;
+use crate::frame::read_next_frame;
+use crate::thread_pool::ThreadPool;
+use crate::{ErrorCode, KvsEngine, KvsError, KvsRequest, KvsResponse, Result};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+pub use self::async_server::AsyncKvsServer;
+pub use self::shutdown::ShutdownHandle;
+
+#[cfg(feature = "async")]
+mod async_server;
+mod shutdown;
+
+/// How long a connection may stay silent before the server closes it.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many requests the server answers on a connection before closing it.
+const DEFAULT_MAX_REQUESTS: u64 = 100_000;
+/// How long shutting down waits for open connections to finish their requests.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+///KvsServer
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    idle_timeout: Duration,
+    max_requests: u64,
+    shutdown_timeout: Duration,
+    shutdown: ShutdownHandle,
+}
+use log::{debug, error, info, warn};
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Create a `KvsServer` with a given storage engine.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Sets how long a connection may wait for its next request before it is closed.
+    ///
+    /// A connection holds a pool thread while it is open, so this also bounds how
+    /// long an idle client can keep a thread from other clients.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how many requests are answered on a connection before it is closed.
+    pub fn max_requests(mut self, max_requests: u64) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Sets how long shutting down waits for open connections to finish the requests
+    /// they have sent before closing them.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Returns a handle that stops `run` from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// accept connections and process them
+    ///
+    /// It returns once shut down through a `ShutdownHandle`, after the open
+    /// connections are drained, the thread pool is joined and the engine is flushed.
+    /// The pool is shut down along with the server, so a server only runs once.
+    pub fn run(&self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.shutdown.listening(listener.local_addr()?);
+        let connections = Arc::new(Connections::default());
+        // A shutdown requested before the listener was bound made no connection to
+        // wake up `accept`, so it is checked before waiting as well as after.
+        while !self.shutdown.is_requested() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Error on accepting client: {}", e);
+                    continue;
+                }
+            };
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let guard = match connections.register(&stream) {
+                Ok(guard) => guard,
+                Err(e) => {
+                    error!("Error on accepting client: {}", e);
+                    continue;
+                }
+            };
+            let engine = self.engine.clone();
+            let idle_timeout = self.idle_timeout;
+            let max_requests = self.max_requests;
+            let pending = PendingConnection(Some(stream));
+            self.pool.spawn(move || {
+                let stream = pending.start();
+                if let Err(e) = serve(engine, stream, idle_timeout, max_requests) {
+                    error!("Error on serving client: {}", e);
+                }
+                drop(guard);
+            })
+        }
+        drop(listener);
+
+        info!("Shutting down");
+        connections.stop_reading();
+        let closed = connections.close_all(self.shutdown_timeout);
+        if closed > 0 {
+            warn!("Closed {} connections with requests in progress", closed);
+        }
+        // Connections end with their job, but the threads may still be finishing.
+        if !self.pool.join(Some(self.shutdown_timeout)) {
+            warn!("Thread pool did not stop in time");
+        }
+        self.engine.flush()
+    }
+}
+
+/// A connection waiting for a pool thread.
+///
+/// A pool with a full queue may drop it instead of running it, in which case the
+/// client is told the server is busy.
+struct PendingConnection(Option<TcpStream>);
+
+impl PendingConnection {
+    fn start(mut self) -> TcpStream {
+        self.0.take().unwrap()
+    }
+}
+
+impl Drop for PendingConnection {
+    fn drop(&mut self) {
+        if let Some(mut stream) = self.0.take() {
+            warn!("Turning a connection away, the server is busy");
+            let response = KvsResponse::Err {
+                code: ErrorCode::Busy,
+                message: "Server busy".to_owned(),
+            };
+            if let Err(e) = response.write_to(&mut stream) {
+                debug!("Error on answering a busy connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Answers the requests of one connection in order until the client closes it, it
+/// stays idle for `idle_timeout` or `max_requests` requests have been answered.
+///
+/// Responses are only flushed once no further request is buffered, so a client
+/// pipelining requests gets its responses back in few writes.
+fn serve<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    idle_timeout: Duration,
+    max_requests: u64,
+) -> Result<()> {
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    for _ in 0..max_requests {
+        let request = match read_next_frame(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(KvsError::Io(ref e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                debug!("Closing idle connection");
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        handle(&engine, request).write_to(&mut writer)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn handle<E: KvsEngine>(engine: &E, request: KvsRequest) -> KvsResponse {
+    match request {
+        KvsRequest::Get { key } => match engine.get_bytes(&key) {
+            Ok(Some(value)) => KvsResponse::Value(value),
+            Ok(None) => KvsResponse::NotFound,
+            Err(e) => e.into(),
+        },
+        KvsRequest::Set { key, value, ttl } => {
+            let result = match ttl {
+                Some(ttl) => engine.set_bytes_with_ttl(&key, &value, Duration::from_secs(ttl)),
+                None => engine.set_bytes(&key, &value),
+            };
+            match result {
+                Ok(()) => KvsResponse::Set,
+                Err(e) => e.into(),
+            }
+        }
+        KvsRequest::Remove { key } => match engine.remove_bytes(&key) {
+            Ok(()) => KvsResponse::Removed,
+            Err(e) => e.into(),
+        },
+        KvsRequest::Scan { start, end, limit } => {
+            let start = start.map_or(Bound::Unbounded, Bound::Included);
+            let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+            match engine
+                .scan_bytes((start, end), limit)
+                .and_then(Iterator::collect)
+            {
+                Ok(entries) => KvsResponse::Entries(entries),
+                Err(e) => e.into(),
+            }
+        }
+        KvsRequest::ScanPrefix { prefix, limit } => {
+            match engine
+                .scan_prefix_bytes(&prefix, limit)
+                .and_then(Iterator::collect)
+            {
+                Ok(entries) => KvsResponse::Entries(entries),
+                Err(e) => e.into(),
+            }
+        }
+        KvsRequest::Batch { batch } => match engine.apply_batch(batch) {
+            Ok(()) => KvsResponse::BatchApplied,
+            Err(e) => e.into(),
+        },
+        KvsRequest::Cas { key, expected, new } => {
+            match engine.compare_and_swap_bytes(&key, expected.as_deref(), new.as_deref()) {
+                Ok(Ok(())) => KvsResponse::Cas {
+                    applied: true,
+                    current: None,
+                },
+                Ok(Err(e)) => KvsResponse::Cas {
+                    applied: false,
+                    current: e.current,
+                },
+                Err(e) => e.into(),
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Stops a running `KvsServer` or `AsyncKvsServer` from another thread.
+///
+/// `run` then stops accepting connections, answers the requests the open connections
+/// have already sent, flushes the engine and returns.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    /// Address the server listens on, once it is bound.
+    addr: Mutex<Option<SocketAddr>>,
+    /// Tasks of an `AsyncKvsServer` to wake up on shutdown.
+    #[cfg(feature = "async")]
+    waiting: Mutex<Waiting>,
+}
+
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct Waiting {
+    next_id: u64,
+    wakers: HashMap<u64, Waker>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down. It does not wait for the server to stop.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        #[cfg(feature = "async")]
+        for (_, waker) in self.inner.waiting.lock().unwrap().wakers.drain() {
+            waker.wake();
+        }
+        let addr = *self.inner.addr.lock().unwrap();
+        if let Some(addr) = addr {
+            // Wakes up the accept loop, which then sees the request.
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    pub(super) fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Runs `future` until it completes, or until a shutdown is requested, in which
+    /// case it is dropped and `None` is returned.
+    #[cfg(feature = "async")]
+    pub(super) fn until_requested<F: Future + Unpin>(&self, future: F) -> UntilRequested<'_, F> {
+        UntilRequested {
+            handle: self,
+            future,
+            id: None,
+        }
+    }
+
+    /// Records the address the server accepts connections on.
+    pub(super) fn listening(&self, mut addr: SocketAddr) {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        *self.inner.addr.lock().unwrap() = Some(addr);
+    }
+}
+
+/// Future returned by `ShutdownHandle::until_requested`.
+#[cfg(feature = "async")]
+pub(super) struct UntilRequested<'a, F> {
+    handle: &'a ShutdownHandle,
+    future: F,
+    /// Key of the waker registered in `ShutdownState::waiting`.
+    id: Option<u64>,
+}
+
+#[cfg(feature = "async")]
+impl<F: Future + Unpin> Future for UntilRequested<'_, F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<F::Output>> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        let handle = self.handle;
+        if handle.is_requested() {
+            return Poll::Ready(None);
+        }
+        let mut waiting = handle.inner.waiting.lock().unwrap();
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                waiting.next_id += 1;
+                waiting.next_id
+            }
+        };
+        waiting.wakers.insert(id, cx.waker().clone());
+        drop(waiting);
+        self.id = Some(id);
+        // `shutdown` may have taken the wakers between the first check and the insert.
+        if handle.is_requested() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F> Drop for UntilRequested<'_, F> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.handle.inner.waiting.lock().unwrap().wakers.remove(&id);
+        }
+    }
+}
+
+/// The open connections of a server, so shutting down can wait for them.
+#[derive(Default)]
+pub(super) struct Connections {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, TcpStream>>,
+    closed: Condvar,
+}
+
+impl Connections {
+    /// Tracks `stream` until the returned guard is dropped.
+    pub(super) fn register(self: &Arc<Self>, stream: &TcpStream) -> io::Result<ConnectionGuard> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.streams.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(ConnectionGuard {
+            id,
+            connections: Arc::clone(self),
+        })
+    }
+
+    /// Shuts down the reading half of every connection.
+    ///
+    /// Requests already received can still be read, after which reads end as if
+    /// the client had closed the connection. Responses can still be written.
+    pub(super) fn stop_reading(&self) {
+        for stream in self.streams.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Waits up to `timeout` for every connection to close, then closes the remaining
+    /// ones. Returns how many had to be closed.
+    pub(super) fn close_all(&self, timeout: Duration) -> usize {
+        let streams = self.streams.lock().unwrap();
+        let (streams, _) = self
+            .closed
+            .wait_timeout_while(streams, timeout, |streams| !streams.is_empty())
+            .unwrap();
+        for stream in streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        streams.len()
+    }
+}
+
+/// Keeps a connection registered in `Connections` while it is being served.
+pub(super) struct ConnectionGuard {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.streams.lock().unwrap().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -0,0 +1,66 @@
+use crossbeam::crossbeam_channel::{bounded, Receiver, TryRecvError};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+/// Waits for the result of a job spawned with `ThreadPool::spawn_with_handle`.
+///
+/// Dropping the handle detaches the job, which still runs.
+pub struct JobHandle<T> {
+    rx: Receiver<thread::Result<T>>,
+}
+
+/// Why a job spawned with `ThreadPool::spawn_with_handle` gave back no value.
+pub enum JobError {
+    /// The job panicked. Holds the panic payload, which `std::panic::resume_unwind`
+    /// can pass on to the caller.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The pool dropped the job without running it, because it was shut down or its
+    /// queue was full.
+    Dropped,
+}
+
+impl<T: Send + 'static> JobHandle<T> {
+    /// Wraps `job` so that running it sends its result to the returned handle.
+    pub(super) fn wrap<F>(job: F) -> (impl FnOnce() + Send + 'static, Self)
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = bounded(1);
+        let job = move || {
+            // The panic is handed to the caller instead of unwinding the pool thread.
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(job)));
+        };
+        (job, JobHandle { rx })
+    }
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish and returns its value.
+    pub fn join(self) -> Result<T, JobError> {
+        match self.rx.recv() {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(_) => Err(JobError::Dropped),
+        }
+    }
+
+    /// Returns the value of the job if it has finished, or gives the handle back if
+    /// it is still queued or running.
+    pub fn try_join(self) -> Result<Result<T, JobError>, Self> {
+        match self.rx.try_recv() {
+            Ok(result) => Ok(result.map_err(JobError::Panicked)),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(JobError::Dropped)),
+        }
+    }
+}
+
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(_) => f.write_str("Panicked(..)"),
+            JobError::Dropped => f.write_str("Dropped"),
+        }
+    }
+}
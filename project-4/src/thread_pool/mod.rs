@@ -2,14 +2,20 @@
 //! the `ThreadPool` trait.
 
 use crate::Result;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
+mod handle;
 mod naive;
 mod rayon;
 mod shared_queue;
+mod work_stealing;
 
+pub use self::handle::{JobError, JobHandle};
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
-pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::shared_queue::{OverflowPolicy, SharedQueueStats, SharedQueueThreadPool};
+pub use self::work_stealing::WorkStealingThreadPool;
 
 /// The trait that all thread pools should implement.
 pub trait ThreadPool {
@@ -27,7 +33,93 @@ pub trait ThreadPool {
     /// Spawning always succeeds, but if the function panics the threadpool continues
     /// to operate with the same number of threads &mdash; the thread count is not
     /// reduced nor is the thread pool destroyed, corrupted or invalidated.
+    ///
+    /// A pool with a bounded queue may drop the function without running it when the
+    /// queue is full, see `OverflowPolicy`.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool and returns a handle to wait for the
+    /// value it returns.
+    ///
+    /// A panic in the function is caught and handed to the handle as a
+    /// `JobError::Panicked`, so it does not reach the pool thread.
+    fn spawn_with_handle<F, T>(&self, job: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = JobHandle::wrap(job);
+        self.spawn(job);
+        handle
+    }
+
+    /// Stops taking new jobs. Jobs spawned afterwards are dropped without running,
+    /// while the ones already spawned still run.
+    fn shutdown(&self);
+
+    /// Shuts the pool down and waits until every spawned job has finished and the
+    /// threads have exited, or until `timeout` has passed.
+    ///
+    /// Returns false if it gave up waiting.
+    fn join(&self, timeout: Option<Duration>) -> bool;
+}
+
+/// Number of running threads or jobs, which can be waited on to drop to zero.
+///
+/// Decrementing is left to a guard or a `Drop` impl so that a panic cannot leave the
+/// count too high.
+#[derive(Default)]
+struct ActiveCount {
+    count: Mutex<usize>,
+    zero: Condvar,
+}
+
+impl ActiveCount {
+    /// Counts one more until the returned guard is dropped.
+    fn enter(self: &Arc<Self>) -> ActiveGuard {
+        self.increment();
+        ActiveGuard(Arc::clone(self))
+    }
+
+    fn increment(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn decrement(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.zero.notify_all();
+        }
+    }
+
+    /// Waits for the count to drop to zero. Returns false if `timeout` passed first.
+    fn wait_zero(&self, timeout: Option<Duration>) -> bool {
+        let mut count = self.count.lock().unwrap();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while *count > 0 {
+            count = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.zero.wait_timeout(count, deadline - now).unwrap().0
+                }
+                None => self.zero.wait(count).unwrap(),
+            };
+        }
+        true
+    }
+}
+
+/// Decrements an `ActiveCount` when dropped, including by a panic.
+struct ActiveGuard(Arc<ActiveCount>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.decrement();
+    }
 }
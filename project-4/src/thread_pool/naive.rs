@@ -1,19 +1,44 @@
-use super::ThreadPool;
+use super::{ActiveCount, ThreadPool};
 use crate::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 ///NaiveThreadPool
-pub struct NaiveThreadPool;
+///
+/// Every job gets a thread of its own.
+#[derive(Default)]
+pub struct NaiveThreadPool {
+    closed: AtomicBool,
+    running: Arc<ActiveCount>,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool::default())
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        let running = self.running.enter();
+        thread::spawn(move || {
+            let _running = running;
+            job()
+        });
+    }
+
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn join(&self, timeout: Option<Duration>) -> bool {
+        self.shutdown();
+        self.running.wait_zero(timeout)
     }
 }
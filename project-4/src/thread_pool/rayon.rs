@@ -1,22 +1,57 @@
-use super::ThreadPool;
-use crate::Result;
+use super::{ActiveCount, ThreadPool};
+use crate::{KvsError, Result};
 use rayon;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 ///RayonThreadPool
-pub struct RayonThreadPool(rayon::ThreadPool);
+///
+/// Jobs are handed to rayon with `rayon::ThreadPool::spawn`, so spawning returns
+/// without waiting for them.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    closed: AtomicBool,
+    /// Rayon does not expose its threads, so `join` waits for the jobs instead.
+    jobs: Arc<ActiveCount>,
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
-            .build()?;
-        Ok(RayonThreadPool(pool))
+            // Without a handler rayon aborts the process when a spawned job panics.
+            // With one the panic is caught and the thread goes on to the next job.
+            .panic_handler(|_| ())
+            .build()
+            .map_err(|e| KvsError::Other(e.to_string()))?;
+        Ok(RayonThreadPool {
+            pool,
+            closed: AtomicBool::new(false),
+            jobs: Arc::new(ActiveCount::default()),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.install(job);
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        let running = self.jobs.enter();
+        self.pool.spawn(move || {
+            let _running = running;
+            job()
+        });
+    }
+
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn join(&self, timeout: Option<Duration>) -> bool {
+        self.shutdown();
+        self.jobs.wait_zero(timeout)
     }
 }
@@ -1,42 +1,160 @@
-use super::ThreadPool;
+use super::{ActiveCount, ThreadPool};
 use crate::Result;
-use crossbeam::crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam::crossbeam_channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
 
 ///SharedQueueThreadPool
+///
+/// Jobs wait in a queue shared by the threads. The queue created by `new` is
+/// unbounded, while the one created by `bounded` holds at most `capacity` jobs and
+/// applies an `OverflowPolicy` to the jobs spawned when it is full.
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    /// Taken by `shutdown`, which lets the threads exit once the queue is empty.
+    tx: RwLock<Option<Sender<Job>>>,
+    /// Kept to take the oldest job out of a full queue.
+    rx: Receiver<Job>,
+    overflow: OverflowPolicy,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    workers: Arc<ActiveCount>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = unbounded();
+/// What `SharedQueueThreadPool::spawn` does when the queue of a bounded pool is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Waits until a thread takes a job off the queue.
+    Block,
+    /// Drops the new job without running it.
+    Reject,
+    /// Drops the job that has waited longest, then queues the new one.
+    DropOldest,
+}
+
+/// Counters of a `SharedQueueThreadPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedQueueStats {
+    /// Number of jobs waiting for a thread.
+    pub queued: usize,
+    /// Number of jobs dropped by `OverflowPolicy::Reject`.
+    pub rejected: u64,
+    /// Number of jobs dropped by `OverflowPolicy::DropOldest`.
+    pub dropped: u64,
+}
+
+impl SharedQueueThreadPool {
+    /// Creates a pool whose queue holds at most `capacity` jobs.
+    ///
+    /// Jobs dropped by the overflow policy are never run, but whatever they own is
+    /// dropped on the spawning thread, so they can still clean up.
+    pub fn bounded(threads: u32, capacity: usize, overflow: OverflowPolicy) -> Result<Self> {
+        let (tx, rx) = bounded(capacity);
+        Ok(SharedQueueThreadPool::start(threads, tx, rx, overflow))
+    }
+
+    /// Returns the current queue depth and how many jobs have been dropped.
+    pub fn stats(&self) -> SharedQueueStats {
+        SharedQueueStats {
+            queued: self.rx.len(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn start(threads: u32, tx: Sender<Job>, rx: Receiver<Job>, overflow: OverflowPolicy) -> Self {
+        let workers = Arc::new(ActiveCount::default());
         for _ in 0..threads {
-            let rx = rx.clone();
-            let worker = Worker(rx);
+            workers.increment();
+            let worker = Worker {
+                rx: rx.clone(),
+                workers: Arc::clone(&workers),
+            };
             thread::spawn(move || worker.run());
         }
-        Ok(SharedQueueThreadPool { tx })
+        SharedQueueThreadPool {
+            tx: RwLock::new(Some(tx)),
+            rx,
+            overflow,
+            rejected: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            workers,
+        }
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (tx, rx) = unbounded();
+        Ok(SharedQueueThreadPool::start(
+            threads,
+            tx,
+            rx,
+            OverflowPolicy::Block,
+        ))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.tx
-            .send(Box::new(job))
-            .expect("The thread pool has no thread.");
+        let tx = self.tx.read().unwrap();
+        let tx = match tx.as_ref() {
+            Some(tx) => tx,
+            None => return,
+        };
+        let mut job: Job = Box::new(job);
+        if self.overflow == OverflowPolicy::Block {
+            tx.send(job).expect("The thread pool has no thread.");
+            return;
+        }
+        loop {
+            job = match tx.try_send(job) {
+                Ok(()) => return,
+                Err(TrySendError::Full(job)) => job,
+                Err(TrySendError::Disconnected(_)) => unreachable!("the pool holds a receiver"),
+            };
+            if self.overflow == OverflowPolicy::Reject {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            // A thread may empty the queue first, in which case nothing is dropped.
+            if self.rx.try_recv().is_ok() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn shutdown(&self) {
+        self.tx.write().unwrap().take();
+    }
+
+    fn join(&self, timeout: Option<Duration>) -> bool {
+        self.shutdown();
+        self.workers.wait_zero(timeout)
     }
 }
 
-struct Worker(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+/// A thread of the pool, counted in `workers` until it exits.
+struct Worker {
+    rx: Receiver<Job>,
+    workers: Arc<ActiveCount>,
+}
 
 impl Drop for Worker {
     fn drop(&mut self) {
         if thread::panicking() {
-            let rx = self.0.clone();
-            let worker = Worker(rx);
+            // The new thread takes over the place of this one in `workers`.
+            let worker = Worker {
+                rx: self.rx.clone(),
+                workers: Arc::clone(&self.workers),
+            };
             thread::spawn(move || worker.run());
+        } else {
+            self.workers.decrement();
         }
     }
 }
@@ -44,7 +162,7 @@ impl Drop for Worker {
 impl Worker {
     fn run(&self) {
         loop {
-            if let Ok(job) = self.0.recv() {
+            if let Ok(job) = self.rx.recv() {
                 job();
             } else {
                 break;
@@ -0,0 +1,183 @@
+use super::{ActiveCount, ThreadPool};
+use crate::Result;
+use crossbeam::deque::{self, Injector, Steal, Stealer};
+use std::iter;
+use std::mem;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How many times a thread that found no job looks again before it goes to sleep.
+const IDLE_ROUNDS: u32 = 32;
+
+///WorkStealingThreadPool
+///
+/// Spawned jobs go to a global queue. Every thread moves batches of them to a deque
+/// of its own, and a thread with nothing left to run steals from the deques of the
+/// others, so the threads rarely contend on a single queue.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    workers: Arc<ActiveCount>,
+}
+
+/// The state shared by the pool and its threads.
+struct Shared {
+    injector: Injector<Job>,
+    /// One per thread, to steal from its deque.
+    stealers: Vec<Stealer<Job>>,
+    closed: AtomicBool,
+    /// Number of threads waiting on `wake`, so spawning only locks `sleep` when a
+    /// thread has to be woken up.
+    sleeping: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn wake_one(&self) {
+        // Pairs with the fence in `Worker::sleep`: either the thread going to sleep
+        // sees the new job, or this sees the thread.
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _sleep = self.sleep.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let locals: Vec<_> = (0..threads).map(|_| deque::Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: locals.iter().map(deque::Worker::stealer).collect(),
+            closed: AtomicBool::new(false),
+            sleeping: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+        });
+        let workers = Arc::new(ActiveCount::default());
+        for local in locals {
+            workers.increment();
+            let worker = Worker {
+                local,
+                shared: Arc::clone(&shared),
+                workers: Arc::clone(&workers),
+            };
+            thread::spawn(move || worker.run());
+        }
+        Ok(WorkStealingThreadPool { shared, workers })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        self.shared.injector.push(Box::new(job));
+        self.shared.wake_one();
+    }
+
+    fn shutdown(&self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        let _sleep = self.shared.sleep.lock().unwrap();
+        self.shared.wake.notify_all();
+    }
+
+    fn join(&self, timeout: Option<Duration>) -> bool {
+        self.shutdown();
+        self.workers.wait_zero(timeout)
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        // The threads wait for jobs as long as the pool is open.
+        self.shutdown();
+    }
+}
+
+/// A thread of the pool with its deque, counted in `workers` until it exits.
+struct Worker {
+    local: deque::Worker<Job>,
+    shared: Arc<Shared>,
+    workers: Arc<ActiveCount>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // The new thread takes over the deque of this one, along with the jobs
+            // left in it, and its place in `workers`.
+            let worker = Worker {
+                local: mem::replace(&mut self.local, deque::Worker::new_fifo()),
+                shared: Arc::clone(&self.shared),
+                workers: Arc::clone(&self.workers),
+            };
+            thread::spawn(move || worker.run());
+        } else {
+            self.workers.decrement();
+        }
+    }
+}
+
+impl Worker {
+    fn run(&self) {
+        let mut idle_rounds = 0;
+        loop {
+            if let Some(job) = self.find_job() {
+                idle_rounds = 0;
+                job();
+            } else if idle_rounds < IDLE_ROUNDS {
+                // Jobs often come in bursts, and waking a sleeping thread costs more
+                // than giving up the CPU for a moment.
+                idle_rounds += 1;
+                thread::yield_now();
+            } else if self.shared.closed.load(Ordering::SeqCst) {
+                break;
+            } else {
+                self.sleep();
+            }
+        }
+    }
+
+    /// Takes a job from the deque of this thread, else a batch from the global
+    /// queue, else steals one from another thread.
+    fn find_job(&self) -> Option<Job> {
+        if let Some(job) = self.local.pop() {
+            return Some(job);
+        }
+        let shared = &self.shared;
+        let job = iter::repeat_with(|| {
+            shared
+                .injector
+                .steal_batch_and_pop(&self.local)
+                .or_else(|| shared.stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success);
+        if !self.local.is_empty() {
+            // Another thread can take part of the batch.
+            shared.wake_one();
+        }
+        job
+    }
+
+    fn sleep(&self) {
+        let shared = &self.shared;
+        let sleep = shared.sleep.lock().unwrap();
+        shared.sleeping.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        // A job may also wait in the deque of a thread busy with another one.
+        let idle = shared.injector.is_empty() && shared.stealers.iter().all(Stealer::is_empty);
+        if idle && !shared.closed.load(Ordering::SeqCst) {
+            let _sleep = shared.wake.wait(sleep).unwrap();
+        }
+        shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+    }
+}
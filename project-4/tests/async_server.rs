@@ -0,0 +1,274 @@
+#![cfg(feature = "async")]
+
+use assert_cmd::prelude::*;
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, CompareAndSwapError, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsRequest, KvsResponse, Result, WriteBatch,
+};
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use tokio::time;
+
+/// Starts an async server over a `KvStore` in a temporary directory.
+fn start_server(
+    addr: &str,
+    configure: fn(AsyncKvsServer<KvStore>) -> AsyncKvsServer<KvStore>,
+) -> (SocketAddr, TempDir) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let server = configure(AsyncKvsServer::new(store));
+    thread::spawn(move || Runtime::new().unwrap().block_on(server.run(addr)).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    (addr, temp_dir)
+}
+
+#[test]
+fn async_client_requests() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4025", |server| server);
+    Runtime::new()?.block_on(async {
+        let mut client = AsyncKvsClient::connect(addr).await?;
+
+        client.set(b"key1", b"value1").await?;
+        client.set(b"key2", b"value2").await?;
+        assert_eq!(client.get(b"key1").await?, Some(b"value1".to_vec()));
+        assert_eq!(client.get(b"key3").await?, None);
+
+        client.remove(b"key1").await?;
+        assert!(matches!(
+            client.remove(b"key1").await,
+            Err(KvsError::KeyNotFound)
+        ));
+
+        let mut batch = WriteBatch::new();
+        batch.set("key3", "value3").remove("key2");
+        client.apply_batch(batch).await?;
+        assert_eq!(
+            client.scan(None, None, None).await?,
+            vec![(b"key3".to_vec(), b"value3".to_vec())]
+        );
+        assert_eq!(client.scan_prefix(b"key2", None).await?, vec![]);
+
+        assert_eq!(
+            client
+                .compare_and_swap(b"key3", Some(b"value0"), None)
+                .await?,
+            Err(CompareAndSwapError {
+                current: Some(b"value3".to_vec())
+            })
+        );
+        assert_eq!(
+            client
+                .compare_and_swap(b"key3", Some(b"value3"), None)
+                .await?,
+            Ok(())
+        );
+        assert_eq!(client.get(b"key3").await?, None);
+        Ok(())
+    })
+}
+
+// Both clients talk to the async server and reconnect when it closes connections.
+#[test]
+fn async_server_closes_connections() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4026", |server| {
+        server
+            .max_requests(2)
+            .idle_timeout(Duration::from_millis(200))
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    let requests: Vec<_> = (0..5)
+        .map(|i| KvsRequest::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: b"value".to_vec(),
+            ttl: None,
+        })
+        .collect();
+    assert!(client
+        .pipeline(&requests)?
+        .iter()
+        .all(|response| matches!(response, KvsResponse::Set)));
+    thread::sleep(Duration::from_millis(500));
+
+    Runtime::new()?.block_on(async {
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        for i in 0..5 {
+            assert_eq!(
+                client.get(format!("key{}", i).as_bytes()).await?,
+                Some(b"value".to_vec())
+            );
+        }
+        time::delay_for(Duration::from_millis(500)).await;
+        client.remove(b"key0").await?;
+        Ok(())
+    })
+}
+
+// Open connections are tasks, so many idle clients do not keep others waiting.
+#[test]
+fn async_server_many_connections() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4027", |server| server);
+    let mut clients = (0..64)
+        .map(|_| KvsClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, client) in clients.iter_mut().enumerate().rev() {
+        client.set(format!("key{}", i).as_bytes(), b"value")?;
+    }
+    assert_eq!(clients[0].scan_prefix(b"key", None)?.len(), 64);
+    Ok(())
+}
+
+// Shutting down answers the requests already sent, closes idle connections right
+// away and flushes the engine before `run` returns.
+#[test]
+fn async_server_shutdown() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4039".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = AsyncKvsServer::new(store);
+    let handle = server.shutdown_handle();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        tx.send(Runtime::new().unwrap().block_on(server.run(addr)))
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut idle = KvsClient::connect(addr)?;
+    idle.set(b"key0", b"value0")?;
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut requests = Vec::new();
+    for i in 0..100 {
+        let request = KvsRequest::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: b"value".to_vec(),
+            ttl: None,
+        };
+        request.write_to(&mut requests)?;
+    }
+    // A request cut in two is still answered if the rest arrives after shutting down.
+    let mut last = Vec::new();
+    KvsRequest::Get {
+        key: b"key0".to_vec(),
+    }
+    .write_to(&mut last)?;
+    let (head, tail) = last.split_at(last.len() / 2);
+    requests.extend_from_slice(head);
+    stream.write_all(&requests)?;
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    handle.shutdown();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(tail)?;
+    for _ in 0..100 {
+        assert!(matches!(
+            KvsResponse::read_from(&mut reader)?,
+            KvsResponse::Set
+        ));
+    }
+    assert!(matches!(
+        KvsResponse::read_from(&mut reader)?,
+        KvsResponse::Value(value) if value == b"value"
+    ));
+    rx.recv_timeout(Duration::from_secs(5))
+        .expect("server did not stop")?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(idle.get(b"key0").is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value".to_owned()));
+
+    // A shutdown requested before the server is listening still stops it.
+    let server = AsyncKvsServer::new(store);
+    server.shutdown_handle().shutdown();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        tx.send(Runtime::new().unwrap().block_on(server.run(addr)))
+            .unwrap()
+    });
+    rx.recv_timeout(Duration::from_secs(5))
+        .expect("server did not stop")?;
+    Ok(())
+}
+
+#[test]
+fn cli_async_runtime() {
+    let addr = "127.0.0.1:4028";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--runtime", "async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-server --runtime async` stops accepting connections and exits cleanly on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn cli_async_sigterm() {
+    let addr = "127.0.0.1:4040";
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--runtime", "async"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let status = Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Server stopped"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
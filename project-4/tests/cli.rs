@@ -1,7 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::{KvsRequest, KvsResponse, WriteBatch};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -272,7 +275,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -335,3 +338,469 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn cli_scan(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--engine", engine, "--addr", addr], &temp_dir);
+
+    for key in &["a", "b/1", "b/2", "c"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, &format!("value-{}", key), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a\tvalue-a\nb/1\tvalue-b/1\nb/2\tvalue-b/2\nc\tvalue-c\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--start", "b", "--end", "c", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b/1\tvalue-b/1\nb/2\tvalue-b/2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "b/", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b/1\tvalue-b/1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "b/", "--start", "a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_scan_kvs_engine() {
+    cli_scan("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_scan_sled_engine() {
+    cli_scan("sled", "127.0.0.1:4007");
+}
+
+fn batch_request(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--engine", engine, "--addr", addr], &temp_dir);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    match send_request(addr, &KvsRequest::Batch { batch }) {
+        KvsResponse::BatchApplied => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+    let key1 = b"key1".to_vec();
+    match send_request(addr, &KvsRequest::Get { key: key1.clone() }) {
+        KvsResponse::NotFound => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+    match send_request(addr, &KvsRequest::Remove { key: key1 }) {
+        KvsResponse::NotFound => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+}
+
+#[test]
+fn batch_request_kvs_engine() {
+    batch_request("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn batch_request_sled_engine() {
+    batch_request("sled", "127.0.0.1:4009");
+}
+
+fn cas_request(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--engine", engine, "--addr", addr], &temp_dir);
+
+    let cas = |expected: Option<&str>, new: Option<&str>| {
+        send_request(
+            addr,
+            &KvsRequest::Cas {
+                key: b"key1".to_vec(),
+                expected: expected.map(|value| value.as_bytes().to_vec()),
+                new: new.map(|value| value.as_bytes().to_vec()),
+            },
+        )
+    };
+    match cas(None, Some("value1")) {
+        KvsResponse::Cas { applied: true, .. } => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+    match cas(Some("value2"), Some("value3")) {
+        KvsResponse::Cas {
+            applied: false,
+            current: Some(ref current),
+        } if current == b"value1" => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+    match cas(Some("value1"), Some("value2")) {
+        KvsResponse::Cas { applied: true, .. } => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+}
+
+#[test]
+fn cas_request_kvs_engine() {
+    cas_request("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn cas_request_sled_engine() {
+    cas_request("sled", "127.0.0.1:4011");
+}
+
+fn cli_set_with_ttl(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--engine", engine, "--addr", addr], &temp_dir);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    thread::sleep(Duration::from_millis(1500));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+}
+
+#[test]
+fn cli_set_with_ttl_kvs_engine() {
+    cli_set_with_ttl("kvs", "127.0.0.1:4012");
+}
+
+#[test]
+fn cli_set_with_ttl_sled_engine() {
+    cli_set_with_ttl("sled", "127.0.0.1:4013");
+}
+
+fn binary_request(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--engine", engine, "--addr", addr], &temp_dir);
+
+    let key = vec![0, 0xff, b'\n', 0xc3];
+    let value: Vec<u8> = (0..=255).collect();
+    match send_request(
+        addr,
+        &KvsRequest::Set {
+            key: key.clone(),
+            value: value.clone(),
+            ttl: None,
+        },
+    ) {
+        KvsResponse::Set => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+    match send_request(addr, &KvsRequest::Get { key: key.clone() }) {
+        KvsResponse::Value(ref got) if *got == value => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+    match send_request(
+        addr,
+        &KvsRequest::ScanPrefix {
+            prefix: vec![0],
+            limit: None,
+        },
+    ) {
+        KvsResponse::Entries(ref entries) if *entries == vec![(key, value)] => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+}
+
+#[test]
+fn binary_request_kvs_engine() {
+    binary_request("kvs", "127.0.0.1:4014");
+}
+
+#[test]
+fn binary_request_sled_engine() {
+    binary_request("sled", "127.0.0.1:4015");
+}
+
+// Requests written back to back on one connection are answered in order.
+fn pipelined_requests(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--engine", engine, "--addr", addr], &temp_dir);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut requests = Vec::new();
+    for i in 0..200 {
+        requests.extend_from_slice(&frame(&KvsRequest::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+            ttl: None,
+        }));
+        requests.extend_from_slice(&frame(&KvsRequest::Get {
+            key: format!("key{}", i).into_bytes(),
+        }));
+    }
+    stream.write_all(&requests).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    for i in 0..200 {
+        match KvsResponse::read_from(&mut reader).unwrap() {
+            KvsResponse::Set => {}
+            response => panic!("unexpected response {:?}", response),
+        }
+        match KvsResponse::read_from(&mut reader).unwrap() {
+            KvsResponse::Value(ref value) if *value == format!("value{}", i).into_bytes() => {}
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
+
+#[test]
+fn pipelined_requests_kvs_engine() {
+    pipelined_requests("kvs", "127.0.0.1:4016");
+}
+
+#[test]
+fn pipelined_requests_sled_engine() {
+    pipelined_requests("sled", "127.0.0.1:4017");
+}
+
+// The server closes a connection after its request cap or once it idles too long.
+#[test]
+fn connection_limits() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(
+        &["--addr", addr, "--max-requests", "2", "--idle-timeout", "1"],
+        &temp_dir,
+    );
+
+    let get = frame(&KvsRequest::Get {
+        key: b"key1".to_vec(),
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for _ in 0..3 {
+        stream.write_all(&get).unwrap();
+    }
+    for _ in 0..2 {
+        match KvsResponse::read_from(&mut stream).unwrap() {
+            KvsResponse::NotFound => {}
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(1500));
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn cli_exec() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&["--addr", addr], &temp_dir);
+
+    let mut script = String::new();
+    for i in 0..300 {
+        script += &format!("set key{} value {}\nget key{}\n", i, i, i);
+    }
+    script += "rm key0\nget key0\n";
+    let mut expected: String = (0..300).map(|i| format!("value {}\n", i)).collect();
+    expected += "Key not found\n";
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["exec", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(script)
+        .assert()
+        .success()
+        .stdout(expected);
+}
+
+// `kvs-server` serves requests with every thread pool and logs the one it uses.
+#[test]
+fn server_cli_thread_pools() {
+    for (pool, addr) in &[
+        ("naive", "127.0.0.1:4031"),
+        ("shared", "127.0.0.1:4032"),
+        ("rayon", "127.0.0.1:4033"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr, "--pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        child.kill().expect("server exited before killed");
+        let output = child.wait_with_output().unwrap();
+        let log = String::from_utf8_lossy(&output.stderr);
+        assert!(log.contains(&format!("Thread pool: {}", pool)), "{}", log);
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4034", "--pool", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server` stops accepting connections and exits cleanly on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn server_cli_sigterm() {
+    let addr = "127.0.0.1:4030";
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let status = Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Server stopped"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+/// A `kvs-server` running in the background, killed when dropped so that a failed
+/// assertion does not leave it running.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let killed = self.0.kill();
+        if !thread::panicking() {
+            killed.expect("server exited before killed");
+        }
+        let _ = self.0.wait();
+    }
+}
+
+/// Starts `kvs-server` with `args` in `dir` and waits for it to listen.
+fn start_server(args: &[&str], dir: &TempDir) -> ServerProcess {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    ServerProcess(child)
+}
+
+fn frame(request: &KvsRequest) -> Vec<u8> {
+    let mut buf = Vec::new();
+    request.write_to(&mut buf).unwrap();
+    buf
+}
+
+fn send_request(addr: &str, request: &KvsRequest) -> KvsResponse {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    request.write_to(&mut stream).unwrap();
+    KvsResponse::read_from(&mut stream).unwrap()
+}
@@ -0,0 +1,331 @@
+use kvs::thread_pool::{NaiveThreadPool, OverflowPolicy, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    CompareAndSwapError, ErrorCode, KvStore, KvsClient, KvsClientOptions, KvsClientPoolOptions,
+    KvsEngine, KvsError, KvsRequest, KvsResponse, KvsServer, Result, WriteBatch,
+};
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Starts a server over a `KvStore` in a temporary directory.
+fn start_server(
+    addr: &str,
+    configure: fn(KvsServer<KvStore, NaiveThreadPool>) -> KvsServer<KvStore, NaiveThreadPool>,
+) -> (SocketAddr, TempDir) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let server = configure(KvsServer::new(store, NaiveThreadPool::new(1).unwrap()));
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    (addr, temp_dir)
+}
+
+#[test]
+fn client_requests() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4020", |server| server);
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set(b"key1", b"value1")?;
+    client.set(b"key2", b"value2")?;
+    assert_eq!(client.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(client.get(b"key3")?, None);
+
+    client.remove(b"key1")?;
+    assert!(matches!(client.remove(b"key1"), Err(KvsError::KeyNotFound)));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3").remove("key2");
+    client.apply_batch(batch)?;
+    assert_eq!(
+        client.scan(None, None, None)?,
+        vec![(b"key3".to_vec(), b"value3".to_vec())]
+    );
+    assert_eq!(client.scan_prefix(b"key2", None)?, vec![]);
+
+    assert_eq!(
+        client.compare_and_swap(b"key3", Some(b"value0"), None)?,
+        Err(CompareAndSwapError {
+            current: Some(b"value3".to_vec())
+        })
+    );
+    assert_eq!(
+        client.compare_and_swap(b"key3", Some(b"value3"), None)?,
+        Ok(())
+    );
+    assert_eq!(client.get(b"key3")?, None);
+    Ok(())
+}
+
+// The client reconnects when the server closes the connection between requests.
+#[test]
+fn client_reconnects() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4021", |server| {
+        server
+            .max_requests(2)
+            .idle_timeout(Duration::from_millis(200))
+    });
+    let mut client = KvsClient::connect(addr)?;
+
+    for i in 0..5 {
+        client.set(format!("key{}", i).as_bytes(), b"value")?;
+    }
+    thread::sleep(Duration::from_millis(500));
+    for i in 0..5 {
+        assert_eq!(
+            client.get(format!("key{}", i).as_bytes())?,
+            Some(b"value".to_vec())
+        );
+    }
+
+    // Requests that cannot be resent are not sent on a connection closed for being idle.
+    thread::sleep(Duration::from_millis(500));
+    client.remove(b"key0")?;
+    Ok(())
+}
+
+// Requests the server may have applied before closing the connection are only
+// resent if applying them twice is harmless.
+#[test]
+fn client_resends_idempotent_requests_only() -> Result<()> {
+    // Reads one request on each connection and closes it without answering.
+    let listener = TcpListener::bind("127.0.0.1:4036")?;
+    let addr = listener.local_addr()?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    {
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let _ = KvsRequest::read_from(stream.unwrap());
+            }
+        });
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.remove(b"key1"), Err(KvsError::Io(_))));
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // The new connection closing too means the server is going away.
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.set(b"key1", b"value1"),
+        Err(KvsError::Io(_))
+    ));
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[test]
+fn client_read_timeout() -> Result<()> {
+    // Accepts connections but never answers.
+    let listener = TcpListener::bind("127.0.0.1:4022")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let _streams: Vec<_> = listener.incoming().collect();
+    });
+
+    let mut client = KvsClientOptions::new()
+        .connect_timeout(Duration::from_secs(1))
+        .read_timeout(Duration::from_millis(200))
+        .connect(addr)?;
+    let start = Instant::now();
+    assert!(matches!(client.get(b"key1"), Err(KvsError::Io(_))));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+#[test]
+fn pool_shared_between_threads() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4023", |server| server);
+    let pool = KvsClientPoolOptions::new()
+        .min_idle(2)
+        .max_idle(3)
+        .connect(addr)?;
+    assert_eq!(pool.idle_count(), 2);
+
+    let handles: Vec<_> = (0..6)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for j in 0..20 {
+                    let key = format!("key{}-{}", i, j);
+                    pool.get()?.set(key.as_bytes(), b"value")?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert!(pool.idle_count() <= 3);
+
+    let mut client = pool.get()?;
+    assert_eq!(client.scan_prefix(b"key", None)?.len(), 120);
+    Ok(())
+}
+
+// Connections closed by the server or past their lifetime are not handed out.
+#[test]
+fn pool_drops_stale_connections() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4024", |server| {
+        server.idle_timeout(Duration::from_millis(200))
+    });
+    let pool = KvsClientPoolOptions::new().min_idle(1).connect(addr)?;
+    thread::sleep(Duration::from_millis(500));
+    {
+        // The stale connection is replaced, on top of the one checked out.
+        let mut client = pool.get()?;
+        assert_eq!(pool.idle_count(), 1);
+        client.set(b"key1", b"value1")?;
+    }
+    assert_eq!(pool.idle_count(), 2);
+
+    let pool = KvsClientPoolOptions::new()
+        .max_lifetime(Duration::from_millis(100))
+        .connect(addr)?;
+    drop(pool.get()?);
+    assert_eq!(pool.idle_count(), 1);
+    let client = pool.get()?;
+    thread::sleep(Duration::from_millis(150));
+    drop(client);
+    assert_eq!(pool.idle_count(), 0);
+    Ok(())
+}
+
+// Idle connections evicted for being stale are replaced up to `min_idle`.
+#[test]
+fn pool_keeps_min_idle() -> Result<()> {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4037", |server| {
+        server.idle_timeout(Duration::from_millis(200))
+    });
+    let pool = KvsClientPoolOptions::new()
+        .min_idle(3)
+        .max_idle(3)
+        .connect(addr)?;
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(500));
+        let mut client = pool.get()?;
+        assert_eq!(pool.idle_count(), 3);
+        client.set(b"key1", b"value1")?;
+    }
+    assert_eq!(pool.idle_count(), 3);
+    for _ in 0..3 {
+        // The replacements are open connections, not stale ones.
+        let mut client = pool.get()?;
+        assert_eq!(client.get(b"key1")?, Some(b"value1".to_vec()));
+        drop(client);
+    }
+    Ok(())
+}
+
+// Shutting down answers the requests already sent, closes idle connections right
+// away and flushes the engine before `run` returns.
+#[test]
+fn server_shutdown() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4029".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store, NaiveThreadPool::new(2)?);
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut idle = KvsClient::connect(addr)?;
+    idle.set(b"key0", b"value0")?;
+    // Requests received before shutting down are answered, as long as their connection
+    // has been accepted.
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    KvsRequest::Get {
+        key: b"key0".to_vec(),
+    }
+    .write_to(&mut stream)?;
+    KvsResponse::read_from(&mut reader)?;
+    let mut requests = Vec::new();
+    for i in 0..100 {
+        let request = KvsRequest::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: b"value".to_vec(),
+            ttl: None,
+        };
+        request.write_to(&mut requests)?;
+    }
+    stream.write_all(&requests)?;
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    handle.shutdown();
+    for _ in 0..100 {
+        assert!(matches!(
+            KvsResponse::read_from(&mut reader)?,
+            KvsResponse::Set
+        ));
+    }
+    running.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(idle.get(b"key0").is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A shutdown requested before the server is listening still stops it.
+#[test]
+fn server_shutdown_before_run() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4038".parse().unwrap();
+    for delay in &[None, Some(Duration::from_millis(0))] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let server = KvsServer::new(store, NaiveThreadPool::new(1)?);
+        let handle = server.shutdown_handle();
+        if delay.is_none() {
+            handle.shutdown();
+        }
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || tx.send(server.run(addr)).unwrap());
+        if let Some(delay) = delay {
+            thread::sleep(*delay);
+            handle.shutdown();
+        }
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("server did not stop")?;
+    }
+    Ok(())
+}
+
+// A connection that finds the queue of a bounded pool full is told the server is
+// busy, while the queued one is served once a thread frees up.
+#[test]
+fn server_busy() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4035".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::bounded(1, 1, OverflowPolicy::Reject)?;
+    let server = KvsServer::new(store, pool);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut served = KvsClient::connect(addr)?;
+    served.set(b"key1", b"value1")?;
+    let mut queued = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+    let mut rejected = KvsClient::connect(addr)?;
+    assert!(matches!(
+        rejected.get(b"key1"),
+        Err(KvsError::ServerError {
+            code: ErrorCode::Busy,
+            ..
+        })
+    ));
+
+    drop(served);
+    assert_eq!(queued.get(b"key1")?, Some(b"value1".to_vec()));
+    Ok(())
+}
@@ -1,6 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionPolicy, CompareAndSwapError, KvStore, KvStoreOptions, KvsEngine, KvsError, Result,
+    ScanIter, SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -67,7 +73,10 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
     Ok(())
 }
 
@@ -209,3 +218,810 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Logs written in the old JSON format should still be readable
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A flipped bit should be detected by the record checksum
+#[test]
+fn detect_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    let last = content.len() - 1;
+    content[last] ^= 0x01;
+    fs::write(&log_path, content)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 1, .. })
+    ));
+    Ok(())
+}
+
+// A record cut short by a crash should be dropped from the newest generation
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Corruption in a sealed generation should only be salvaged by `repair`
+#[test]
+fn repair_sealed_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    assert!(KvStore::repair(temp_dir.path())? > 0);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A garbled object in a legacy JSON log is corruption that `repair` can salvage
+#[test]
+fn repair_legacy_json_log() -> Result<()> {
+    let garbled = r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","val{"Set":{"key":"key3","value":"value3"}}"#;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), garbled)?;
+    fs::write(
+        temp_dir.path().join("2.log"),
+        r#"{"Set":{"key":"key4","value":"value4"}}"#,
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 1, .. })
+    ));
+
+    assert!(KvStore::repair(temp_dir.path())? > 0);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    // The newest generation is cut at the garbled object when opening.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), garbled)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Every sync policy should keep data readable across reopens
+#[test]
+fn sync_policies() -> Result<()> {
+    for &policy in &[
+        SyncPolicy::Always,
+        SyncPolicy::EveryN(3),
+        SyncPolicy::Interval(10),
+        SyncPolicy::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .sync_policy(policy)
+            .open(temp_dir.path())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStoreOptions::new()
+            .sync_policy(policy)
+            .open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_sync_policy() -> Result<()> {
+    assert_eq!("always".parse::<SyncPolicy>()?, SyncPolicy::Always);
+    assert_eq!("never".parse::<SyncPolicy>()?, SyncPolicy::Never);
+    assert_eq!("every:100".parse::<SyncPolicy>()?, SyncPolicy::EveryN(100));
+    assert_eq!(
+        "interval:50".parse::<SyncPolicy>()?,
+        SyncPolicy::Interval(50)
+    );
+    assert!("every:0".parse::<SyncPolicy>().is_err());
+    assert!("interval:0".parse::<SyncPolicy>().is_err());
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+    Ok(())
+}
+
+// Concurrent writers committed in groups should all see their own result
+#[test]
+fn concurrent_set_and_remove_sync_always() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                let key = format!("key{}_{}", thread_id, i);
+                store.set(key.clone(), format!("value{}", i)).unwrap();
+                if i % 2 == 0 {
+                    store.remove(key.clone()).unwrap();
+                    assert!(store.remove(key).is_err());
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..16 {
+        for i in 0..50 {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}_{}", thread_id, i))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// Writes racing with background compaction should never be lost
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let value = value.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..300 {
+                for key_id in 0..10 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key, format!("{}{}", value, iter)).unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let expected = Some(format!("{}{}", value, 299));
+    for thread_id in 0..8 {
+        for key_id in 0..10 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, expected);
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..10 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// Manual compaction should only run when asked to and reclaim all stale bytes
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let stats = store.stats();
+    assert!(stats.stale_bytes > stats.live_bytes);
+    assert!(stats.live_bytes > 0);
+    assert_eq!(stats.generations, 1);
+
+    store.compact()?;
+    let compacted = store.stats();
+    assert_eq!(compacted.stale_bytes, 0);
+    assert_eq!(compacted.live_bytes, stats.live_bytes);
+    assert_eq!(compacted.generations, 2);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Keys overwritten while a compaction runs are counted as stale only once
+#[test]
+fn stats_with_overwrites_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let stop = Arc::clone(&stop);
+        handles.push(thread::spawn(move || {
+            let mut iter = 0;
+            while !stop.load(Ordering::SeqCst) {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key, format!("value{}", iter)).unwrap();
+                }
+                iter += 1;
+            }
+        }));
+    }
+    for _ in 0..10 {
+        store.compact()?;
+    }
+    stop.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Every byte of the logs left on disk is either live or stale.
+    let log_bytes: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    let stats = store.stats();
+    assert_eq!(stats.stale_bytes + stats.live_bytes, log_bytes);
+    Ok(())
+}
+
+// Every caller of `compact` waits for the running compaction and sees its result
+#[test]
+fn concurrent_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..10000 {
+            store.set(format!("key{}", key_id), format!("{:0100}", iter))?;
+        }
+    }
+    // The compaction into generation 2 fails once it has copied every record.
+    fs::create_dir(temp_dir.path().join("2.log"))?;
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                let result = store.compact();
+                assert!(!store.stats().compacting);
+                result
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert!(handle.join().unwrap().is_err());
+    }
+    Ok(())
+}
+
+// A failed compaction keeps counting the stale bytes it was to reclaim
+#[test]
+fn failed_compaction_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let before = store.stats();
+    assert!(before.stale_bytes > 0);
+
+    // The compaction into generation 2 cannot create its temporary file.
+    let blocker = temp_dir.path().join("2.log.compacting");
+    fs::create_dir(&blocker)?;
+    assert!(store.compact().is_err());
+    let stats = store.stats();
+    assert!(!stats.compacting);
+    assert_eq!(stats.stale_bytes, before.stale_bytes);
+    assert_eq!(stats.live_bytes, before.live_bytes);
+    store.set("key0".to_owned(), "value3".to_owned())?;
+    assert!(store.stats().stale_bytes > before.stale_bytes);
+
+    fs::remove_dir(&blocker)?;
+    store.compact()?;
+    assert_eq!(store.stats().stale_bytes, 0);
+    assert_eq!(store.get("key0".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Reopening the store adds generations until the limit triggers compaction
+#[test]
+fn max_generations_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    for i in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(log_count(), 5);
+
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::MaxGenerations(4))
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats().generations, 6);
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+    assert_eq!(log_count(), 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..6 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Compaction should leave a hint file that open can use or safely ignore
+#[test]
+fn open_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.compact()?;
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let hint_path = temp_dir.path().join("2.hint");
+    assert!(hint_path.exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    drop(store);
+
+    // Garbage after the last record makes the compacted log unreplayable, but leaves
+    // every record the hint points to readable, so opening only works through the hint
+    let log_path = temp_dir.path().join("2.log");
+    let log = fs::read(&log_path)?;
+    let mut garbage = log.clone();
+    garbage.extend_from_slice(&[0xff; 64]);
+    fs::write(&log_path, &garbage)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    drop(store);
+
+    // A corrupt hint file falls back to reading the log
+    let mut content = fs::read(&hint_path)?;
+    content[10] ^= 0x01;
+    fs::write(&hint_path, content)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 2, .. })
+    ));
+    fs::write(&log_path, &log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Scans should return pairs in key order within the range or prefix
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "b/1", "b/2", "b/3", "c"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("b/2".to_owned())?;
+
+    let keys = |iter: ScanIter| -> Result<Vec<String>> {
+        iter.map(|entry| entry.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(keys(store.scan(.., None)?)?, vec!["a", "b/1", "b/3", "c"]);
+    assert_eq!(
+        keys(store.scan("b".to_owned().."c".to_owned(), None)?)?,
+        vec!["b/1", "b/3"]
+    );
+    assert_eq!(keys(store.scan("b/3".to_owned().., Some(1))?)?, vec!["b/3"]);
+    assert_eq!(
+        keys(store.scan_prefix("b/".to_owned(), None)?)?,
+        vec!["b/1", "b/3"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("b/".to_owned(), Some(1))?)?,
+        vec!["b/1"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("d".to_owned(), None)?)?,
+        Vec::<String>::new()
+    );
+
+    let mut iter = store.scan_prefix("c".to_owned(), None)?;
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("c".to_owned(), "value-c".to_owned()))
+    );
+    assert!(iter.next().is_none());
+    Ok(())
+}
+
+// Keys and values that are not valid UTF-8 should round-trip through the log
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = [0, 0xff, b'\n', 0xc3];
+    let value: Vec<u8> = (0..=255).collect();
+    store.set_bytes(&key, &value)?;
+    store.set_bytes(&[0, 1], b"")?;
+
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert!(store
+        .get(String::from_utf8_lossy(&key).into_owned())?
+        .is_none());
+    assert!(store.get_bytes(&[0, 1])?.is_some());
+
+    // Reopen from disk.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    let entries = store
+        .scan_bytes(vec![0, 0x80].., None)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries, vec![(key.to_vec(), value)]);
+
+    // The String API refuses values that are not UTF-8.
+    store.set_bytes(b"key1", &[0xff])?;
+    assert!(store.get("key1".to_owned()).is_err());
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    Ok(())
+}
+
+#[test]
+fn apply_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned())
+        .remove("key3".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    store.apply_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn drop_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    // Cut into the commit marker, which leaves every command of the batch intact.
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?,
+        Err(CompareAndSwapError {
+            current: Some(b"value1".to_vec())
+        })
+    );
+    assert_eq!(
+        store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned())
+        )?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?,
+        Err(CompareAndSwapError { current: None })
+    );
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, None)?,
+        Ok(())
+    );
+
+    Ok(())
+}
+
+// Increments a counter from many threads; every increment must be counted once
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || {
+            barrier.wait();
+            for _ in 0..50 {
+                let mut current = store.get("counter".to_owned()).unwrap();
+                loop {
+                    let next = current.as_ref().unwrap().parse::<u32>().unwrap() + 1;
+                    match store
+                        .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                        .unwrap()
+                    {
+                        Ok(()) => break,
+                        Err(e) => {
+                            current = e.current.map(|value| String::from_utf8(value).unwrap())
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    let entries: Vec<_> = store.scan(.., None)?.collect::<Result<_>>()?;
+    assert_eq!(entries, vec![("key2".to_owned(), "value2".to_owned())]);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Setting the key again without a ttl makes it permanent
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("live".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+
+    let live_bytes = store.stats().live_bytes;
+    store.compact()?;
+    assert!(store.stats().live_bytes * 50 < live_bytes);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("live".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("live".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -64,7 +66,290 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+/// Spawns two jobs that can only finish if they run at the same time.
+fn spawn_concurrent<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (a_tx, a_rx) = mpsc::channel();
+    let (b_tx, b_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+    for (tx, rx) in vec![(a_tx, b_rx), (b_tx, a_rx)] {
+        let done_tx = done_tx.clone();
+        pool.spawn(move || {
+            tx.send(()).unwrap();
+            let met = rx.recv_timeout(Duration::from_secs(5)).is_ok();
+            done_tx.send(met).unwrap();
+        })
+    }
+    assert!(done_rx.recv().unwrap());
+    assert!(done_rx.recv().unwrap());
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_concurrent() -> Result<()> {
+    spawn_concurrent::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_concurrent() -> Result<()> {
+    spawn_concurrent::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_concurrent() -> Result<()> {
+    spawn_concurrent::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_concurrent() -> Result<()> {
+    spawn_concurrent::<WorkStealingThreadPool>()
+}
+
+fn spawn_with_handle<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let handles: Vec<_> = (0..8)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(values, vec![0, 2, 4, 6, 8, 10, 12, 14]);
+
+    let (tx, rx) = mpsc::channel::<()>();
+    let handle = pool.spawn_with_handle(move || rx.recv().is_err());
+    let handle = handle
+        .try_join()
+        .err()
+        .expect("job finished before it was released");
+    drop(tx);
+    assert!(handle.join().unwrap());
+
+    let handle = pool.spawn_with_handle(|| -> () {
+        panic_control::disable_hook_in_current_thread();
+        panic!("job failed");
+    });
+    match handle.join() {
+        Err(JobError::Panicked(payload)) => {
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"))
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    // The pool still runs jobs after one of them panicked.
+    assert_eq!(pool.spawn_with_handle(|| 1).join().unwrap(), 1);
+
+    pool.shutdown();
+    assert!(matches!(
+        pool.spawn_with_handle(|| 1).join(),
+        Err(JobError::Dropped)
+    ));
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<WorkStealingThreadPool>()
+}
+
+/// Keeps the only thread of `pool` busy until the returned sender is dropped.
+fn occupy<P: ThreadPool>(pool: &P) -> mpsc::Sender<()> {
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        let _ = release_rx.recv();
+    });
+    started_rx.recv().unwrap();
+    release_tx
+}
+
+#[test]
+fn shared_queue_thread_pool_reject() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 1, OverflowPolicy::Reject)?;
+    let release = occupy(&pool);
+    let (tx, rx) = mpsc::channel();
+    for i in 0..3 {
+        let tx = tx.clone();
+        pool.spawn(move || tx.send(i).unwrap());
+    }
+    assert_eq!(
+        pool.stats(),
+        SharedQueueStats {
+            queued: 1,
+            rejected: 2,
+            dropped: 0
+        }
+    );
+
+    drop(release);
+    drop(tx);
+    assert_eq!(rx.iter().collect::<Vec<_>>(), vec![0]);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_oldest() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 1, OverflowPolicy::DropOldest)?;
+    let release = occupy(&pool);
+    let (tx, rx) = mpsc::channel();
+    for i in 0..3 {
+        let tx = tx.clone();
+        pool.spawn(move || tx.send(i).unwrap());
+    }
+    assert_eq!(
+        pool.stats(),
+        SharedQueueStats {
+            queued: 1,
+            rejected: 0,
+            dropped: 2
+        }
+    );
+
+    drop(release);
+    drop(tx);
+    assert_eq!(rx.iter().collect::<Vec<_>>(), vec![2]);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_block() -> Result<()> {
+    let pool = Arc::new(SharedQueueThreadPool::bounded(1, 1, OverflowPolicy::Block)?);
+    let release = occupy(&*pool);
+    let (tx, rx) = mpsc::channel();
+    let spawned = Arc::new(AtomicBool::new(false));
+    let spawner = {
+        let pool = Arc::clone(&pool);
+        let spawned = Arc::clone(&spawned);
+        thread::spawn(move || {
+            for i in 0..2 {
+                let tx = tx.clone();
+                pool.spawn(move || tx.send(i).unwrap());
+            }
+            spawned.store(true, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    assert!(!spawned.load(Ordering::SeqCst));
+    assert_eq!(pool.stats().queued, 1);
+
+    drop(release);
+    spawner.join().unwrap();
+    assert_eq!(rx.iter().collect::<Vec<_>>(), vec![0, 1]);
+    Ok(())
+}
+
+fn join_waits_for_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..8 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    assert!(pool.join(Some(Duration::from_secs(5))));
+    assert_eq!(counter.load(Ordering::SeqCst), 8);
+
+    // Jobs spawned after shutting down are dropped.
+    let late = Arc::clone(&counter);
+    pool.spawn(move || {
+        late.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(pool.join(None));
+    assert_eq!(counter.load(Ordering::SeqCst), 8);
+    Ok(())
+}
+
+fn join_timeout<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (tx, rx) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        let _ = rx.recv();
+    });
+    assert!(!pool.join(Some(Duration::from_millis(100))));
+    drop(tx);
+    assert!(pool.join(Some(Duration::from_secs(5))));
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<NaiveThreadPool>()?;
+    join_timeout::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<SharedQueueThreadPool>()?;
+    join_timeout::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<RayonThreadPool>()?;
+    join_timeout::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<WorkStealingThreadPool>()?;
+    join_timeout::<WorkStealingThreadPool>()
+}
+
+// Threads replacing the ones killed by panics are joined too.
+fn join_after_panics<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    for _ in 0..100 {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+    }
+    assert!(pool.join(Some(Duration::from_secs(5))));
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_join_after_panics() -> Result<()> {
+    join_after_panics::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_join_after_panics() -> Result<()> {
+    join_after_panics::<WorkStealingThreadPool>()
+}